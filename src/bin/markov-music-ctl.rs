@@ -0,0 +1,438 @@
+/*
+ * bin/markov-music-ctl.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Sends commands to a running daemon over its control socket.
+
+#![deny(missing_debug_implementations)]
+
+extern crate clap;
+extern crate markov_music;
+
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind, Shell, SubCommand};
+use markov_music::config::Config;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Lines, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+/// The daemon couldn't carry out the command.
+const EXIT_FAILED: i32 = 1;
+
+/// The arguments were invalid, either here or according to the daemon.
+const EXIT_INVALID: i32 = 2;
+
+/// The daemon doesn't know the command.
+const EXIT_NO_COMMAND: i32 = 3;
+
+/// The daemon couldn't be reached, or its reply couldn't be understood.
+const EXIT_CONNECTION: i32 = 4;
+
+/// The configuration file couldn't be read.
+const EXIT_CONFIG: i32 = 5;
+
+fn app() -> App<'static, 'static> {
+    let song = |name| Arg::with_name(name).required(true).help("The song's URI");
+    let weight = || Arg::with_name("weight").help("How much evidence to add, 1 by default");
+
+    App::new("markov-music-ctl")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author("Ammon Smith")
+        .about("Controls a running markov-music daemon")
+        .after_help(
+            "Exits with 1 if the daemon could not carry out the command, 2 if the arguments \
+             were invalid, 3 if the daemon does not know the command, 4 if it could not be \
+             reached, and 5 if the configuration file could not be read.",
+        )
+        .max_term_width(110)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Use a specific configuration file instead of the default"),
+        )
+        .arg(
+            Arg::with_name("socket")
+                .short("s")
+                .long("socket")
+                .value_name("PATH")
+                .help("The daemon's socket, instead of the one in the configuration"),
+        )
+        .arg(
+            Arg::with_name("raw")
+                .short("r")
+                .long("raw")
+                .help("Print replies exactly as received"),
+        )
+        .subcommand(SubCommand::with_name("ping").about("Checks that the daemon is running"))
+        .subcommand(
+            SubCommand::with_name("recommend")
+                .about("Turns queue management on or off, or shows whether it is on")
+                .arg(Arg::with_name("state").possible_values(&["on", "off"])),
+        )
+        .subcommand(
+            SubCommand::with_name("strategy")
+                .about("Changes how the next song is chosen")
+                .arg(
+                    Arg::with_name("name")
+                        .required(true)
+                        .possible_values(&["proportional", "softmax", "epsilon-greedy", "top-k", "thompson"]),
+                )
+                .arg(Arg::with_name("param").help("The temperature, epsilon or k")),
+        )
+        .subcommand(SubCommand::with_name("reconcile").about("Matches songs which have moved in the library"))
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Forgets songs deleted from the library")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .short("n")
+                        .long("dry-run")
+                        .help("Only list the songs which would be forgotten"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("next")
+                .about("Lists what has been learned to follow a song")
+                .arg(song("song")),
+        )
+        .subcommand(
+            SubCommand::with_name("top")
+                .about("Lists the transitions most likely to be enjoyed")
+                .arg(Arg::with_name("count").help("How many to list, 10 by default")),
+        )
+        .subcommand(SubCommand::with_name("predict").about("Lists what could be chosen to follow the current song"))
+        .subcommand(SubCommand::with_name("stats").about("Shows the size of the chain and of its storage"))
+        .subcommand(
+            SubCommand::with_name("like")
+                .about("Adds evidence that one song goes well after another")
+                .arg(song("from"))
+                .arg(song("to"))
+                .arg(weight()),
+        )
+        .subcommand(
+            SubCommand::with_name("dislike")
+                .about("Adds evidence that one song does not go well after another")
+                .arg(song("from"))
+                .arg(song("to"))
+                .arg(weight()),
+        )
+        .subcommand(
+            SubCommand::with_name("forget")
+                .about("Forgets every transition out of or into a song")
+                .arg(song("song")),
+        )
+        .subcommand(
+            SubCommand::with_name("like-current")
+                .about("Adds evidence that the current song goes well after the previous one")
+                .arg(weight()),
+        )
+        .subcommand(
+            SubCommand::with_name("dislike-current")
+                .about("Adds evidence that the current song does not go well after the previous one")
+                .arg(weight()),
+        )
+        .subcommand(SubCommand::with_name("subscribe").about("Prints events from the daemon as they happen"))
+        .subcommand(SubCommand::with_name("quit").about("Stops the daemon"))
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Prints a shell completion script")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&Shell::variants()),
+                ),
+        )
+}
+
+/// Quotes an argument if the daemon would otherwise split it.
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(&[' ', '"', '\\'][..]) {
+        return arg.to_owned();
+    }
+
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for c in arg.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Builds the line to send for a subcommand.
+fn command(name: &str, matches: &ArgMatches) -> String {
+    let mut line = name.to_ascii_uppercase();
+
+    if name == "gc" && matches.is_present("dry-run") {
+        line.push_str(" DRY");
+    }
+
+    for arg in &["state", "name", "param", "song", "from", "to", "count", "weight"] {
+        if let Some(value) = matches.value_of(arg) {
+            line.push(' ');
+            line.push_str(&quote(value));
+        }
+    }
+
+    line
+}
+
+/// How a command was answered.
+#[derive(Debug)]
+enum Reply {
+    Ok(Vec<String>),
+    Err(Vec<String>, String),
+}
+
+type Connection = Lines<BufReader<UnixStream>>;
+
+fn send(socket: &Path, line: &str) -> io::Result<Connection> {
+    let mut stream = UnixStream::connect(socket)?;
+    writeln!(stream, "{}", line)?;
+
+    Ok(BufReader::new(stream).lines())
+}
+
+/// Reads the next reply, or event once subscribed.
+fn receive(connection: &mut Connection) -> io::Result<Reply> {
+    let mut lines = Vec::new();
+    for received in connection {
+        let received = received?;
+        if received == "OK" {
+            return Ok(Reply::Ok(lines));
+        }
+
+        if let Some(error) = received.strip_prefix("ERR ") {
+            return Ok(Reply::Err(lines, error.to_owned()));
+        }
+
+        lines.push(received);
+    }
+
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before reply was finished"))
+}
+
+/// Prints `key: value` lines in columns. Keys which appear once are
+/// listed first, and repeated ones, each group being one record, are
+/// shown as a table.
+fn print_response(lines: &[String]) {
+    let fields = lines
+        .iter()
+        .map(|line| match line.find(": ") {
+            Some(idx) => (&line[..idx], &line[idx + 2..]),
+            None => ("", line.as_str()),
+        })
+        .collect::<Vec<_>>();
+
+    let mut counts = HashMap::new();
+    for &(key, _) in &fields {
+        *counts.entry(key).or_insert(0) += 1;
+    }
+
+    let (single, repeated): (Vec<_>, Vec<_>) = fields
+        .into_iter()
+        .partition(|&(key, _)| counts[key] == 1);
+
+    let width = single.iter().map(|&(key, _)| key.len()).max().unwrap_or(0);
+    for (key, value) in single {
+        if key.is_empty() {
+            println!("{}", value);
+        } else {
+            println!("{:width$}  {}", key, value, width = width);
+        }
+    }
+
+    if repeated.is_empty() {
+        return;
+    }
+
+    let mut columns = Vec::new();
+    let mut rows: Vec<Vec<&str>> = Vec::new();
+    for (key, value) in repeated {
+        let column = match columns.iter().position(|&column| column == key) {
+            Some(column) => column,
+            None => {
+                columns.push(key);
+                columns.len() - 1
+            },
+        };
+
+        match rows.last_mut() {
+            Some(ref mut row) if row.len() == column => row.push(value),
+            _ => {
+                let mut row = vec![""; column];
+                row.push(value);
+                rows.push(row);
+            },
+        }
+    }
+
+    let widths = (0..columns.len())
+        .map(|idx| {
+            rows.iter()
+                .filter_map(|row| row.get(idx))
+                .map(|value| value.len())
+                .chain(Some(columns[idx].len()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+
+    let print_row = |row: &[&str]| {
+        let last = row.len().saturating_sub(1);
+        let mut line = String::new();
+        for (idx, value) in row.iter().enumerate() {
+            if idx == last {
+                line.push_str(value);
+            } else {
+                line.push_str(&format!("{:width$}  ", value, width = widths[idx]));
+            }
+        }
+
+        println!("{}", line);
+    };
+
+    let header = columns.iter().map(|column| column.to_ascii_uppercase()).collect::<Vec<_>>();
+    print_row(&header.iter().map(String::as_str).collect::<Vec<_>>());
+    for row in &rows {
+        print_row(row);
+    }
+}
+
+fn main() {
+    let matches = match app().get_matches_safe() {
+        Ok(matches) => matches,
+        Err(ref e) if e.kind == ErrorKind::HelpDisplayed || e.kind == ErrorKind::VersionDisplayed => e.exit(),
+        Err(e) => {
+            eprintln!("{}", e.message);
+            exit(EXIT_INVALID);
+        },
+    };
+
+    let (name, sub_matches) = match matches.subcommand() {
+        (name, Some(sub_matches)) => (name, sub_matches),
+        _ => unreachable!("Subcommand is required"),
+    };
+
+    if name == "completions" {
+        let shell = sub_matches
+            .value_of("shell")
+            .and_then(|shell| shell.parse::<Shell>().ok())
+            .expect("Shell is required");
+
+        app().gen_completions_to("markov-music-ctl", shell, &mut io::stdout());
+        return;
+    }
+
+    let socket = match matches.value_of("socket") {
+        Some(path) => PathBuf::from(path),
+        None => match Config::load(matches.value_of("config").map(Path::new)) {
+            Ok(config) => config.daemon.socket,
+            Err(e) => {
+                eprintln!("Error reading configuration: {}", e);
+                exit(EXIT_CONFIG);
+            },
+        },
+    };
+
+    let raw = matches.is_present("raw");
+    let print = |lines: &[String]| {
+        if raw {
+            lines.iter().for_each(|line| println!("{}", line));
+        } else {
+            print_response(lines);
+        }
+    };
+
+    let fail = |e: io::Error| -> ! {
+        eprintln!("Error talking to daemon at {}: {}", socket.display(), e);
+        exit(EXIT_CONNECTION);
+    };
+
+    let mut connection = send(&socket, &command(name, sub_matches)).unwrap_or_else(|e| fail(e));
+    let reply = receive(&mut connection).unwrap_or_else(|e| fail(e));
+
+    match reply {
+        Reply::Ok(lines) => {
+            print(&lines);
+
+            // Each event is framed like a reply, separated here by blank lines
+            if name == "subscribe" {
+                loop {
+                    if let Reply::Ok(lines) = receive(&mut connection).unwrap_or_else(|e| fail(e)) {
+                        print(&lines);
+                        println!();
+                    }
+                }
+            }
+        },
+        Reply::Err(lines, error) => {
+            lines.iter().for_each(|line| println!("{}", line));
+
+            let (kind, message) = match error.find(' ') {
+                Some(idx) => (&error[..idx], &error[idx + 1..]),
+                None => (error.as_str(), ""),
+            };
+
+            eprintln!("Error: {}", message);
+            exit(match kind {
+                "NOCMD" => EXIT_NO_COMMAND,
+                "INVARG" => EXIT_INVALID,
+                _ => EXIT_FAILED,
+            });
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use markov_music::internals::split_args;
+
+    #[test]
+    fn quote_round_trip() {
+        let args = [
+            "a.flac",
+            "Artist/Album/01 Song.flac",
+            "",
+            " ",
+            r#"say "hi""#,
+            r"back\slash",
+            "trailing\\",
+            r#"\"both\""#,
+        ];
+
+        for arg in &args {
+            let quoted = quote(arg);
+            assert_eq!(split_args(&quoted).expect("Argument not split"), vec![*arg], "{}", quoted);
+        }
+
+        let line = args.iter().map(|arg| quote(arg)).collect::<Vec<_>>().join(" ");
+        assert_eq!(split_args(&line).expect("Arguments not split"), args);
+    }
+}
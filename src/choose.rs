@@ -18,28 +18,617 @@
  * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use config::StrategyConfig;
 use rand::Rng;
-use std::collections::HashMap;
-use std::hash::Hash;
+use rand::distributions::{Gamma, IndependentSample};
+use std::f32;
+use std::fmt::Debug;
 
-macro_rules! len {
-    ($map:tt) => { $map.len() as f32 }
+/// The outcome of a selection strategy: either one of the candidates,
+/// or a request to explore a song outside of them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Choice<T> {
+    Pick(T),
+    Explore,
 }
 
-pub fn random_key<'a, K: Eq + Hash, V>(map: &'a HashMap<K, V>, rng: &mut Rng) -> Option<&'a K> {
-    let index = rng.next_f32() * len!(map);
-    map.keys().nth(index as usize)
+impl<T> Choice<T> {
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Choice<U> {
+        match self {
+            Choice::Pick(value) => Choice::Pick(f(value)),
+            Choice::Explore => Choice::Explore,
+        }
+    }
+}
+
+/// Decides which candidate to play next, given their weights.
+///
+/// The weights are always passed in the same order for the same
+/// candidates, so results only depend on the random number generator.
+/// Returns `None` if there is nothing suitable to choose.
+pub trait SelectionStrategy: Debug {
+    fn choose(&self, weights: &[f32], rng: &mut dyn Rng) -> Option<Choice<usize>>;
+
+    /// Like `choose`, but given the evidence for each candidate as the
+    /// `(alpha, beta)` parameters of a Beta posterior. By default this
+    /// chooses using the posterior means as weights.
+    fn choose_posterior(&self, posteriors: &[(f32, f32)], rng: &mut dyn Rng) -> Option<Choice<usize>> {
+        let means = posteriors
+            .iter()
+            .map(|&(alpha, beta)| alpha / (alpha + beta))
+            .collect::<Vec<_>>();
+
+        self.choose(&means, rng)
+    }
+
+    /// Whether this strategy always picks in proportion to the posterior
+    /// means. If so, precomputed alias tables can be sampled instead.
+    fn is_proportional(&self) -> bool {
+        false
+    }
+}
+
+/// Picks candidates with probability proportional to their weights.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Proportional;
+
+impl SelectionStrategy for Proportional {
+    fn choose(&self, weights: &[f32], rng: &mut dyn Rng) -> Option<Choice<usize>> {
+        weighted_index(weights, rng).map(Choice::Pick)
+    }
+
+    fn is_proportional(&self) -> bool {
+        true
+    }
+}
+
+/// Picks candidates with probability proportional to `exp(weight / temperature)`.
+/// Low temperatures favor the strongest transitions, high ones flatten the distribution.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Softmax {
+    pub temperature: f32,
+}
+
+impl SelectionStrategy for Softmax {
+    fn choose(&self, weights: &[f32], rng: &mut dyn Rng) -> Option<Choice<usize>> {
+        let max = weights
+            .iter()
+            .cloned()
+            .filter(|weight| weight.is_finite())
+            .fold(f32::NEG_INFINITY, f32::max);
+
+        if !max.is_finite() {
+            return None;
+        }
+
+        let scaled = weights
+            .iter()
+            .map(|&weight| match weight.is_finite() {
+                true => ((weight - max) / self.temperature).exp(),
+                false => 0.0,
+            })
+            .collect::<Vec<_>>();
+
+        weighted_index(&scaled, rng).map(Choice::Pick)
+    }
+}
+
+/// Explores a random song with probability `epsilon`,
+/// otherwise always picks the strongest transition.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EpsilonGreedy {
+    pub epsilon: f32,
+}
+
+impl SelectionStrategy for EpsilonGreedy {
+    fn choose(&self, weights: &[f32], rng: &mut dyn Rng) -> Option<Choice<usize>> {
+        if rng.next_f32() < self.epsilon {
+            return Some(Choice::Explore);
+        }
+
+        let mut best: Option<(usize, f32)> = None;
+        for (idx, &weight) in weights.iter().enumerate() {
+            if !weight.is_finite() || weight <= 0.0 {
+                continue;
+            }
+
+            match best {
+                Some((_, max)) if max >= weight => (),
+                _ => best = Some((idx, weight)),
+            }
+        }
+
+        best.map(|(idx, _)| Choice::Pick(idx))
+    }
+}
+
+/// Picks proportionally among only the `k` strongest transitions.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TopK {
+    pub k: usize,
+}
+
+impl SelectionStrategy for TopK {
+    fn choose(&self, weights: &[f32], rng: &mut dyn Rng) -> Option<Choice<usize>> {
+        let mut order = (0..weights.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| {
+            weights[b]
+                .partial_cmp(&weights[a])
+                .unwrap_or_else(|| weights[a].is_nan().cmp(&weights[b].is_nan()))
+        });
+
+        let mut kept = vec![0.0; weights.len()];
+        for &idx in order.iter().take(self.k) {
+            kept[idx] = weights[idx];
+        }
+
+        weighted_index(&kept, rng).map(Choice::Pick)
+    }
 }
 
-pub fn roulette_wheel<'a, K: Eq + Hash>(map: &'a HashMap<K, f32>, rng: &mut Rng) -> Option<&'a K> {
-    let mut rand = rng.next_f32() * len!(map);
-    for (key, &weight) in map.iter() {
+/// Thompson sampling: draws a probability from each candidate's posterior
+/// and picks the highest, so uncertain transitions still get tried while
+/// well-established ones are favored.
+///
+/// Without any evidence to sample from, it falls back to proportional choice.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Thompson;
+
+impl SelectionStrategy for Thompson {
+    fn choose(&self, weights: &[f32], rng: &mut dyn Rng) -> Option<Choice<usize>> {
+        weighted_index(weights, rng).map(Choice::Pick)
+    }
+
+    fn choose_posterior(&self, posteriors: &[(f32, f32)], mut rng: &mut dyn Rng) -> Option<Choice<usize>> {
+        let mut best: Option<(usize, f64)> = None;
+        for (idx, &(alpha, beta)) in posteriors.iter().enumerate() {
+            let sample = match sample_beta(alpha, beta, &mut rng) {
+                Some(sample) => sample,
+                None => continue,
+            };
+
+            match best {
+                Some((_, max)) if max >= sample => (),
+                _ => best = Some((idx, sample)),
+            }
+        }
+
+        best.map(|(idx, _)| Choice::Pick(idx))
+    }
+}
+
+/// Draws from a Beta distribution, as the ratio of two Gamma variates.
+/// Returns `None` if the parameters are not positive and finite.
+fn sample_beta<R: Rng>(alpha: f32, beta: f32, rng: &mut R) -> Option<f64> {
+    let usable = |param: f32| param.is_finite() && param > 0.0;
+    if !usable(alpha) || !usable(beta) {
+        return None;
+    }
+
+    let x = Gamma::new(f64::from(alpha), 1.0).ind_sample(rng);
+    let y = Gamma::new(f64::from(beta), 1.0).ind_sample(rng);
+
+    match x + y {
+        total if total > 0.0 => Some(x / total),
+        _ => None,
+    }
+}
+
+pub fn strategy(config: &StrategyConfig) -> Box<dyn SelectionStrategy> {
+    match *config {
+        StrategyConfig::Proportional => Box::new(Proportional),
+        StrategyConfig::Softmax { temperature } => Box::new(Softmax { temperature }),
+        StrategyConfig::EpsilonGreedy { epsilon } => Box::new(EpsilonGreedy { epsilon }),
+        StrategyConfig::TopK { k } => Box::new(TopK { k }),
+        StrategyConfig::Thompson => Box::new(Thompson),
+    }
+}
+
+/// Picks an index with probability proportional to its weight.
+/// Zero, negative and non-finite weights are never chosen, and
+/// `None` is returned if no weight is positive.
+pub fn weighted_index(weights: &[f32], rng: &mut dyn Rng) -> Option<usize> {
+    let usable = |weight: f32| weight.is_finite() && weight > 0.0;
+    let total = weights
+        .iter()
+        .filter(|&&weight| usable(weight))
+        .map(|&weight| f64::from(weight))
+        .sum::<f64>();
+
+    if total <= 0.0 {
+        return None;
+    }
+
+    let mut rand = rng.next_f64() * total;
+    let mut last = None;
+    for (idx, &weight) in weights.iter().enumerate() {
+        if !usable(weight) {
+            continue;
+        }
+
+        let weight = f64::from(weight);
         if rand < weight {
-            return Some(key);
+            return Some(idx);
         }
 
         rand -= weight;
+        last = Some(idx);
     }
 
-    None
+    // Only reachable through floating point rounding
+    last
+}
+
+/// Samples indices with probability proportional to their weights in
+/// constant time, after linear setup, using Vose's alias method.
+///
+/// As with `weighted_index`, zero, negative and non-finite weights
+/// are never chosen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AliasTable {
+    prob: Vec<f64>,
+    alias: Vec<usize>,
+}
+
+impl AliasTable {
+    /// Builds a table for the given weights, or returns
+    /// `None` if none of them are positive.
+    pub fn new(weights: &[f32]) -> Option<Self> {
+        let usable = |weight: f32| weight.is_finite() && weight > 0.0;
+        let total = weights
+            .iter()
+            .filter(|&&weight| usable(weight))
+            .map(|&weight| f64::from(weight))
+            .sum::<f64>();
+
+        if total <= 0.0 {
+            return None;
+        }
+
+        let len = weights.len();
+        let mut scaled = weights
+            .iter()
+            .map(|&weight| match usable(weight) {
+                true => f64::from(weight) * len as f64 / total,
+                false => 0.0,
+            })
+            .collect::<Vec<_>>();
+
+        let mut small = Vec::new();
+        let mut large = Vec::new();
+        for (idx, &p) in scaled.iter().enumerate() {
+            if p < 1.0 {
+                small.push(idx);
+            } else {
+                large.push(idx);
+            }
+        }
+
+        let mut prob = vec![0.0; len];
+        let mut alias = vec![0; len];
+        while let (Some(&less), Some(&more)) = (small.last(), large.last()) {
+            small.pop();
+            large.pop();
+
+            prob[less] = scaled[less];
+            alias[less] = more;
+
+            scaled[more] = (scaled[more] + scaled[less]) - 1.0;
+            if scaled[more] < 1.0 {
+                small.push(more);
+            } else {
+                large.push(more);
+            }
+        }
+
+        // Whatever is left is only off from 1 by rounding error, except
+        // for unusable weights, which must still never be chosen
+        let fallback = (0..len)
+            .find(|&idx| usable(weights[idx]))
+            .expect("No usable weight despite positive total");
+
+        for idx in large.into_iter().chain(small) {
+            if usable(weights[idx]) {
+                prob[idx] = 1.0;
+            } else {
+                prob[idx] = 0.0;
+                alias[idx] = fallback;
+            }
+        }
+
+        Some(AliasTable { prob, alias })
+    }
+
+    pub fn sample(&self, rng: &mut dyn Rng) -> usize {
+        let len = self.prob.len();
+        let idx = ((rng.next_f64() * len as f64) as usize).min(len - 1);
+
+        if rng.next_f64() < self.prob[idx] {
+            idx
+        } else {
+            self.alias[idx]
+        }
+    }
+}
+
+/// Picks one of the keys uniformly at random. The keys are sorted
+/// first, so the result does not depend on their iteration order.
+pub fn random_key<'a, K, I>(keys: I, rng: &mut dyn Rng) -> Option<&'a K>
+where
+    K: Ord + 'a,
+    I: IntoIterator<Item = &'a K>,
+{
+    let mut keys = keys.into_iter().collect::<Vec<_>>();
+    if keys.is_empty() {
+        return None;
+    }
+
+    keys.sort();
+
+    let index = (rng.next_f64() * keys.len() as f64) as usize;
+    Some(keys[index.min(keys.len() - 1)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use markov::{Chain, Edge};
+    use std::collections::HashMap;
+    use rand::{SeedableRng, XorShiftRng};
+
+    const SAMPLES: usize = 100_000;
+    const TOLERANCE: f64 = 0.01;
+
+    fn rng() -> XorShiftRng {
+        XorShiftRng::from_seed([0x193a_6754, 0xa8a7_d469, 0x9783_0e05, 0x113b_a7bb])
+    }
+
+    /// How much evidence each edge in a test chain has in total, so
+    /// that its posterior mean is within 0.001 of the intended weight.
+    const EVIDENCE: f32 = 1000.0;
+
+    /// Builds a chain where each song follows "start" with a posterior
+    /// mean of its weight, which must be between 0 and 1.
+    fn chain(weights: &HashMap<&'static str, f32>) -> Chain<&'static str> {
+        let mut chain = Chain::new(1);
+        for (&song, &weight) in weights {
+            let edge = Edge {
+                successes: weight * EVIDENCE,
+                failures: (1.0 - weight) * EVIDENCE,
+                updated: 0,
+            };
+
+            chain.set_weight(vec!["start"], song, edge);
+        }
+
+        chain
+    }
+
+    fn proportional_next(chain: &Chain<&'static str>, rng: &mut dyn Rng) -> Option<&'static str> {
+        match chain.next(&["start"], &Proportional, rng) {
+            Some(Choice::Pick(&song)) => Some(song),
+            Some(Choice::Explore) => panic!("Proportional strategy explored"),
+            None => None,
+        }
+    }
+
+    fn frequencies_with(
+        map: &HashMap<&'static str, f32>,
+        strategy: &dyn SelectionStrategy,
+    ) -> HashMap<&'static str, f64> {
+        let chain = chain(map);
+        let mut rng = rng();
+        let mut counts = HashMap::new();
+
+        for _ in 0..SAMPLES {
+            let key = match chain.next(&["start"], strategy, &mut rng).expect("No key chosen") {
+                Choice::Pick(&key) => key,
+                Choice::Explore => "<explore>",
+            };
+
+            *counts.entry(key).or_insert(0) += 1;
+        }
+
+        counts
+            .into_iter()
+            .map(|(key, count)| (key, count as f64 / SAMPLES as f64))
+            .collect()
+    }
+
+    fn frequencies(map: &HashMap<&'static str, f32>) -> HashMap<&'static str, f64> {
+        frequencies_with(map, &Proportional)
+    }
+
+    #[test]
+    fn roulette_wheel_matches_weights() {
+        let map = [("a", 0.1), ("b", 0.2), ("c", 0.3), ("d", 0.4)]
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>();
+        let freqs = frequencies(&map);
+
+        for (key, &weight) in &map {
+            let expected = f64::from(weight);
+            let actual = freqs[key];
+            assert!(
+                (expected - actual).abs() < TOLERANCE,
+                "{}: expected {}, got {}",
+                key,
+                expected,
+                actual,
+            );
+        }
+    }
+
+    #[test]
+    fn roulette_wheel_skips_unusable_weights() {
+        let weights = [0.0, -1.0, 0.5, f32::NAN];
+        let mut rng = rng();
+
+        for _ in 0..SAMPLES {
+            assert_eq!(weighted_index(&weights, &mut rng), Some(2));
+        }
+    }
+
+    #[test]
+    fn roulette_wheel_no_choice() {
+        let mut rng = rng();
+        let empty = HashMap::<&str, f32>::new();
+
+        assert_eq!(proportional_next(&chain(&empty), &mut rng), None);
+        assert_eq!(weighted_index(&[], &mut rng), None);
+        assert_eq!(weighted_index(&[0.0, -2.0], &mut rng), None);
+        assert_eq!(random_key(empty.keys(), &mut rng), None);
+    }
+
+    #[test]
+    fn random_key_uniform() {
+        let map = (0..10).map(|i| (i, ())).collect::<HashMap<_, _>>();
+        let mut rng = rng();
+        let mut counts = [0usize; 10];
+
+        for _ in 0..SAMPLES {
+            counts[*random_key(map.keys(), &mut rng).unwrap()] += 1;
+        }
+
+        for &count in &counts {
+            let freq = count as f64 / SAMPLES as f64;
+            assert!((freq - 0.1).abs() < TOLERANCE, "frequency {}", freq);
+        }
+    }
+
+    #[test]
+    fn deterministic_order() {
+        let edge = Edge {
+            successes: 1.0,
+            failures: 1.0,
+            updated: 0,
+        };
+
+        // Successors are inserted in opposite orders, but must be sampled the same
+        let (mut forward, mut backward) = (Chain::new(1), Chain::new(1));
+        for key in 0..100 {
+            forward.set_weight(vec![100], key, edge);
+            backward.set_weight(vec![100], 99 - key, edge);
+        }
+
+        let (mut rng1, mut rng2) = (rng(), rng());
+        for strategy in &[&Proportional as &dyn SelectionStrategy, &TopK { k: 10 }] {
+            for _ in 0..100 {
+                assert_eq!(
+                    forward.next(&[100], *strategy, &mut rng1),
+                    backward.next(&[100], *strategy, &mut rng2),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn softmax_matches_temperature() {
+        let map = [("a", 0.2), ("b", 0.5), ("c", 0.8)]
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>();
+        let temperature = 0.25;
+        let freqs = frequencies_with(&map, &Softmax { temperature });
+
+        let total = map.values().map(|&w| f64::from(w / temperature).exp()).sum::<f64>();
+        for (key, &weight) in &map {
+            let expected = f64::from(weight / temperature).exp() / total;
+            assert!((expected - freqs[key]).abs() < TOLERANCE, "{}", key);
+        }
+    }
+
+    #[test]
+    fn epsilon_greedy_explores() {
+        let map = [("a", 0.2), ("b", 0.9), ("c", 0.4)]
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>();
+        let freqs = frequencies_with(&map, &EpsilonGreedy { epsilon: 0.2 });
+
+        assert_eq!(freqs.len(), 2);
+        assert!((freqs["<explore>"] - 0.2).abs() < TOLERANCE);
+        assert!((freqs["b"] - 0.8).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn top_k_limits_candidates() {
+        let map = [("a", 0.1), ("b", 0.4), ("c", 0.2), ("d", 0.6)]
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>();
+        let freqs = frequencies_with(&map, &TopK { k: 2 });
+
+        assert_eq!(freqs.len(), 2);
+        assert!((freqs["b"] - 0.4).abs() < TOLERANCE);
+        assert!((freqs["d"] - 0.6).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn alias_table_matches_weights() {
+        let weights = [1.0, 0.0, 2.0, -1.0, 3.0, f32::NAN, 4.0];
+        let table = AliasTable::new(&weights).expect("No table built");
+        let mut rng = rng();
+        let mut counts = [0usize; 7];
+
+        for _ in 0..SAMPLES {
+            counts[table.sample(&mut rng)] += 1;
+        }
+
+        for (idx, &count) in counts.iter().enumerate() {
+            let expected = match weights[idx] {
+                weight if weight > 0.0 => f64::from(weight) / 10.0,
+                _ => 0.0,
+            };
+
+            let actual = count as f64 / SAMPLES as f64;
+            assert!(
+                (expected - actual).abs() < TOLERANCE,
+                "Index {} expected {}, was {}",
+                idx,
+                expected,
+                actual,
+            );
+        }
+
+        assert!(AliasTable::new(&[0.0, -1.0]).is_none());
+        assert!(AliasTable::new(&[]).is_none());
+    }
+
+    #[test]
+    fn thompson_converges_on_listener() {
+        const ROUNDS: usize = 2_000;
+        const MEASURED: usize = 500;
+
+        // How likely a synthetic listener is to enjoy each song after "start"
+        let preferences = [("a", 0.2), ("b", 0.5), ("c", 0.8), ("d", 0.4)]
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>();
+
+        let mut rng = rng();
+        let mut chain = Chain::new(1);
+        for &song in preferences.keys() {
+            chain.modify_weight(vec!["start"], song, 0.0);
+        }
+
+        let mut best = 0;
+        for round in 0..ROUNDS {
+            let song = match chain.next(&["start"], &Thompson, &mut rng) {
+                Some(Choice::Pick(&song)) => song,
+                other => panic!("Unexpected choice: {:?}", other),
+            };
+
+            let enjoyed = rng.next_f64() < preferences[song];
+            chain.modify_weight(vec!["start"], song, if enjoyed { 1.0 } else { -1.0 });
+
+            if round >= ROUNDS - MEASURED && song == "c" {
+                best += 1;
+            }
+        }
+
+        let share = best as f64 / MEASURED as f64;
+        assert!(share > 0.8, "Favorite song picked only {:.1}% of the time", share * 100.0);
+    }
 }
@@ -23,8 +23,9 @@ use std::env;
 use std::fs::File;
 use std::io::prelude::Read;
 use std::path::{Path, PathBuf};
-use utils::HOME_DIR;
-use {toml, Result};
+use tags::Tag;
+use utils::{expand_home, HOME_DIR};
+use {toml, Error, Result};
 
 lazy_static! {
     static ref DEFAULT_CONFIG_PATH: PathBuf = {
@@ -74,10 +75,136 @@ impl Default for MpdConfig {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct QueueConfig {
+    pub recommend: bool,
+    pub upcoming: u32,
+    pub history: u32,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            recommend: true,
+            upcoming: 5,
+            history: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct BackoffConfig {
+    pub tags: Vec<Tag>,
+    pub min_successors: usize,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            tags: vec![Tag::Album, Tag::Artist, Tag::Genre],
+            min_successors: 3,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialEq)]
+#[serde(tag = "strategy", rename_all = "kebab-case")]
+pub enum StrategyConfig {
+    #[default]
+    Proportional,
+    Softmax { temperature: f32 },
+    EpsilonGreedy { epsilon: f32 },
+    TopK { k: usize },
+    Thompson,
+}
+
+impl StrategyConfig {
+    /// Parses a strategy from its name and optional parameter,
+    /// as given over the control socket.
+    pub fn parse(name: &str, param: Option<&str>) -> Option<Self> {
+        let float = || param.and_then(|p| p.parse::<f32>().ok());
+        let strategy = match name {
+            "proportional" => StrategyConfig::Proportional,
+            "softmax" => StrategyConfig::Softmax {
+                temperature: float()?,
+            },
+            "epsilon-greedy" => StrategyConfig::EpsilonGreedy {
+                epsilon: float()?,
+            },
+            "top-k" => StrategyConfig::TopK {
+                k: param?.parse().ok()?,
+            },
+            "thompson" => StrategyConfig::Thompson,
+            _ => return None,
+        };
+
+        if strategy.is_valid() {
+            Some(strategy)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            StrategyConfig::Proportional | StrategyConfig::Thompson => true,
+            StrategyConfig::Softmax { temperature } => temperature.is_finite() && temperature > 0.0,
+            StrategyConfig::EpsilonGreedy { epsilon } => (0.0..=1.0).contains(&epsilon),
+            StrategyConfig::TopK { k } => k > 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LearningConfig {
+    pub order: usize,
+    pub half_life_days: Option<f64>,
+    pub completed: f32,
+    pub skipped_early: f32,
+    pub skipped_late: f32,
+    pub replayed: f32,
+    pub early_skip: f32,
+}
+
+impl LearningConfig {
+    pub fn half_life(&self) -> Option<f64> {
+        self.half_life_days.map(|days| days * 24.0 * 60.0 * 60.0)
+    }
+}
+
+impl Default for LearningConfig {
+    fn default() -> Self {
+        LearningConfig {
+            order: 2,
+            half_life_days: None,
+            completed: 1.0,
+            skipped_early: -1.0,
+            skipped_late: -0.25,
+            replayed: 1.5,
+            early_skip: 0.5,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct Config {
     pub daemon: DaemonConfig,
     pub mpd: MpdConfig,
+
+    #[serde(default)]
+    pub queue: QueueConfig,
+
+    #[serde(default)]
+    pub selection: StrategyConfig,
+
+    #[serde(default)]
+    pub backoff: BackoffConfig,
+
+    #[serde(default)]
+    pub learning: LearningConfig,
 }
 
 impl Config {
@@ -85,10 +212,37 @@ impl Config {
         let mut file = File::open(path.as_ref())?;
         let mut contents = String::new();
         let _ = file.read_to_string(&mut contents)?;
-        let config: Self = toml::from_str(&contents)?;
+        let mut config: Self = toml::from_str(&contents)?;
+
+        config.daemon.storage_file = expand_home(&config.daemon.storage_file);
+        config.daemon.socket = expand_home(&config.daemon.socket);
+
+        if !config.selection.is_valid() {
+            return Err(Error::StaticMsg("Invalid selection strategy parameters"));
+        }
+
+        if config.learning.order == 0 {
+            return Err(Error::StaticMsg("Chain order must be at least 1"));
+        }
+
+        if let Some(days) = config.learning.half_life_days {
+            if !(days.is_finite() && days > 0.0) {
+                return Err(Error::StaticMsg("Half-life must be a positive number of days"));
+            }
+        }
 
         Ok(config)
     }
+
+    /// Reads the given configuration file, or if there is none, the one
+    /// in the default location. Without either, the defaults are used.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => Config::read(path),
+            None if DEFAULT_CONFIG_PATH.is_file() => Config::read(&*DEFAULT_CONFIG_PATH),
+            None => Ok(Config::default()),
+        }
+    }
 }
 
 pub fn parse_args() -> Result<Config> {
@@ -129,13 +283,7 @@ pub fn parse_args() -> Result<Config> {
         )
         .get_matches();
 
-    let mut config = match matches.value_of("config") {
-        Some(path) => Config::read(Path::new(path))?,
-        None if DEFAULT_CONFIG_PATH.is_file() => {
-            Config::read(&*DEFAULT_CONFIG_PATH)?
-        },
-        _ => Config::default(),
-    };
+    let mut config = Config::load(matches.value_of("config").map(Path::new))?;
 
     if let Some(val) = matches.value_of("host") {
         config.mpd.host = val.into();
@@ -151,3 +299,38 @@ pub fn parse_args() -> Result<Config> {
 
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_sections() {
+        let learning = toml::from_str::<LearningConfig>("half_life_days = 30").expect("Section not parsed");
+        assert_eq!(
+            learning,
+            LearningConfig {
+                half_life_days: Some(30.0),
+                ..LearningConfig::default()
+            },
+        );
+
+        let queue = toml::from_str::<QueueConfig>("upcoming = 8").expect("Section not parsed");
+        assert_eq!(
+            queue,
+            QueueConfig {
+                upcoming: 8,
+                ..QueueConfig::default()
+            },
+        );
+
+        let backoff = toml::from_str::<BackoffConfig>("min_successors = 3").expect("Section not parsed");
+        assert_eq!(
+            backoff,
+            BackoffConfig {
+                min_successors: 3,
+                ..BackoffConfig::default()
+            },
+        );
+    }
+}
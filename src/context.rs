@@ -18,52 +18,170 @@
  * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use {markov, Result};
+use Result;
 use config::Config;
-use player::Player;
+use events::{Event, EventBus};
+use model::Model;
+use player::{ListenTracker, Player, PlayerEvent};
+use queue::QueueManager;
+use rand;
+use settings::Settings;
 use std::borrow::{Borrow, BorrowMut};
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug)]
 pub struct Context {
     config: Config,
-    chain: markov::Chain<String>,
+    model: Arc<Mutex<Model>>,
     player: Player,
+    tracker: Arc<Mutex<ListenTracker>>,
+    queue: QueueManager,
+    settings: Arc<Settings>,
+    events: Arc<EventBus>,
 }
 
 impl Context {
     pub fn new(config: Config) -> Result<Self> {
-        let player = Player::new(&config.mpd)?;
+        let mut model = Model::load(&config)?;
+        let mut player = Player::new(&config.mpd)?;
+        player.subscribe()?;
 
-        Ok(Context {
+        // Files may have moved while the daemon wasn't running
+        model.refresh_songs(&mut player)?;
+
+        let tracker = Arc::new(Mutex::new(ListenTracker::new(&config.learning)));
+        let settings = Arc::new(Settings::new(&config));
+
+        if let Some(recommend) = model.saved_recommend()? {
+            settings.set_recommend(recommend);
+        }
+
+        let queue = QueueManager::new(&config, Arc::clone(&settings));
+
+        let mut ctx = Context {
             config,
-            chain: markov::Chain::new(),
+            model: Arc::new(Mutex::new(model)),
             player,
-        })
+            tracker,
+            queue,
+            settings,
+            events: Arc::new(EventBus::new()),
+        };
+
+        // mpd won't report a song which was already playing,
+        // so start tracking it here
+        if let Some(song) = ctx.player.playing().cloned() {
+            ctx.handle(PlayerEvent::SongStarted(song))?;
+        }
+
+        ctx.update_queue()?;
+        Ok(ctx)
+    }
+
+    pub fn settings(&self) -> Arc<Settings> {
+        Arc::clone(&self.settings)
+    }
+
+    pub fn model(&self) -> Arc<Mutex<Model>> {
+        Arc::clone(&self.model)
+    }
+
+    pub fn tracker(&self) -> Arc<Mutex<ListenTracker>> {
+        Arc::clone(&self.tracker)
+    }
+
+    pub fn events(&self) -> Arc<EventBus> {
+        Arc::clone(&self.events)
     }
 
     pub fn wait(&mut self) -> Result<()> {
-        self.player.update()?;
+        let result = self.process();
+        if let Err(ref e) = result {
+            self.events.publish(Event::Error {
+                message: e.to_string(),
+            });
+        }
 
-        // TODO
-        unimplemented!()
+        result
     }
-}
 
-impl Borrow<Config> for Context {
-    fn borrow(&self) -> &Config {
-        &self.config
+    fn process(&mut self) -> Result<()> {
+        let mut queue_dirty = false;
+
+        for event in self.player.update()? {
+            debug!("Player event: {:?}", event);
+
+            match event {
+                PlayerEvent::SongStarted(_)
+                | PlayerEvent::QueueChanged
+                | PlayerEvent::SettingsChanged => queue_dirty = true,
+                _ => (),
+            }
+
+            self.handle(event)?;
+        }
+
+        if queue_dirty {
+            self.update_queue()?;
+        }
+
+        Ok(())
     }
-}
 
-impl Borrow<markov::Chain<String>> for Context {
-    fn borrow(&self) -> &markov::Chain<String> {
-        &self.chain
+    fn update_queue(&mut self) -> Result<()> {
+        let songs = self.queue
+            .update(&mut self.player, &self.model, &mut rand::thread_rng())?;
+
+        if !songs.is_empty() {
+            self.events.publish(Event::Enqueued { songs });
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, event: PlayerEvent) -> Result<()> {
+        let transition = self.tracker
+            .lock()
+            .expect("Tracker lock poisoned")
+            .process(&event);
+
+        if let Some(transition) = transition {
+            let learned = Event::Learned {
+                from: transition.history.last().map(|song| song.file.clone()),
+                to: transition.next.file.clone(),
+                listen: transition.listen,
+                delta: transition.listen.weight_delta(&self.config.learning),
+            };
+
+            let mut model = self.model.lock().expect("Model lock poisoned");
+            model.learn(transition, &self.config)?;
+            self.events.publish(learned);
+        }
+
+        match event {
+            PlayerEvent::SongStarted(ref song) => {
+                info!("Now playing: {}", song.file);
+                self.events.publish(Event::SongStarted {
+                    song: song.file.clone(),
+                });
+            },
+            PlayerEvent::DatabaseChanged => {
+                self.queue.invalidate_library();
+
+                let songs = self.player.songs()?;
+                let mut model = self.model.lock().expect("Model lock poisoned");
+                model.refresh_library(&songs)?;
+            },
+            _ => (),
+        }
+
+        Ok(())
     }
 }
 
-impl BorrowMut<markov::Chain<String>> for Context {
-    fn borrow_mut(&mut self) -> &mut markov::Chain<String> {
-        &mut self.chain
+impl Borrow<Config> for Context {
+    fn borrow(&self) -> &Config {
+        &self.config
     }
 }
 
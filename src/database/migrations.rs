@@ -0,0 +1,224 @@
+/*
+ * database/migrations.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use {Error, Result};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use diesel;
+use super::schema::schema_version;
+
+/// Schema changes, applied in order. The schema version stored in
+/// the database is the number of migrations that have been run.
+///
+/// Only ever append to this list, as existing databases will have
+/// already applied the earlier entries.
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema
+    "
+    CREATE TABLE IF NOT EXISTS associations (
+        song TEXT NOT NULL,
+        next TEXT NOT NULL,
+        weight REAL NOT NULL,
+        PRIMARY KEY (song, next)
+    );
+    ",
+    // 2: Transitions between song tags
+    "
+    CREATE TABLE tag_associations (
+        tag TEXT NOT NULL,
+        value TEXT NOT NULL,
+        next TEXT NOT NULL,
+        weight REAL NOT NULL,
+        PRIMARY KEY (tag, value, next)
+    );
+    ",
+    // 3: Track when associations last changed, for decay
+    "
+    ALTER TABLE associations ADD COLUMN updated INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE tag_associations ADD COLUMN updated INTEGER NOT NULL DEFAULT 0;
+    UPDATE associations SET updated = strftime('%s', 'now');
+    UPDATE tag_associations SET updated = strftime('%s', 'now');
+    ",
+    // 4: Store evidence counts instead of squashed weights.
+    //    Each old weight is carried over as a single observation.
+    "
+    CREATE TABLE associations_new (
+        song TEXT NOT NULL,
+        next TEXT NOT NULL,
+        successes REAL NOT NULL,
+        failures REAL NOT NULL,
+        updated INTEGER NOT NULL,
+        PRIMARY KEY (song, next)
+    );
+    INSERT INTO associations_new
+        SELECT song, next, weight, 1.0 - weight, updated FROM associations;
+    DROP TABLE associations;
+    ALTER TABLE associations_new RENAME TO associations;
+
+    CREATE TABLE tag_associations_new (
+        tag TEXT NOT NULL,
+        value TEXT NOT NULL,
+        next TEXT NOT NULL,
+        successes REAL NOT NULL,
+        failures REAL NOT NULL,
+        updated INTEGER NOT NULL,
+        PRIMARY KEY (tag, value, next)
+    );
+    INSERT INTO tag_associations_new
+        SELECT tag, value, next, weight, 1.0 - weight, updated FROM tag_associations;
+    DROP TABLE tag_associations;
+    ALTER TABLE tag_associations_new RENAME TO tag_associations;
+    ",
+    // 5: Current URIs of each song identity
+    "
+    CREATE TABLE songs (
+        id TEXT PRIMARY KEY NOT NULL,
+        uri TEXT NOT NULL
+    );
+    ",
+    // 6: Song metadata, to recognize files which have moved
+    "
+    ALTER TABLE songs ADD COLUMN artist TEXT;
+    ALTER TABLE songs ADD COLUMN title TEXT;
+    ALTER TABLE songs ADD COLUMN album TEXT;
+    ALTER TABLE songs ADD COLUMN duration INTEGER;
+    ALTER TABLE songs ADD COLUMN missing BOOLEAN NOT NULL DEFAULT 0;
+    ",
+    // 7: Settings changed while running, kept across restarts
+    "
+    CREATE TABLE settings (
+        name TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL
+    );
+    ",
+];
+
+const CREATE_SCHEMA_VERSION: &str = "
+    CREATE TABLE IF NOT EXISTS schema_version (
+        version INTEGER PRIMARY KEY NOT NULL
+    );
+";
+
+pub fn run(conn: &SqliteConnection) -> Result<()> {
+    conn.batch_execute(CREATE_SCHEMA_VERSION)?;
+
+    let current = schema_version::table
+        .select(schema_version::version)
+        .order(schema_version::version.desc())
+        .first::<i32>(conn)
+        .optional()?
+        .unwrap_or(0) as usize;
+
+    if current > MIGRATIONS.len() {
+        return Err(Error::Msg(format!(
+            "Database schema version {} is newer than this program supports ({})",
+            current,
+            MIGRATIONS.len(),
+        )));
+    }
+
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version = idx as i32 + 1;
+        info!("Migrating database to schema version {}", version);
+
+        conn.transaction::<(), Error, _>(|| {
+            conn.batch_execute(migration)?;
+            diesel::insert_into(schema_version::table)
+                .values(schema_version::version.eq(version))
+                .execute(conn)?;
+
+            Ok(())
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::{Association, Database, SqliteDatabase, StorageStats};
+    use std::{env, fs, process};
+
+    #[test]
+    fn legacy_weights() {
+        let path = env::temp_dir().join(format!("markov-music-{}-legacy.sqlite", process::id()));
+        let _ = fs::remove_file(&path);
+
+        // The schema from before migrations were tracked
+        SqliteConnection::establish(path.to_str().unwrap())
+            .unwrap()
+            .batch_execute(
+                "
+                CREATE TABLE associations (
+                    song TEXT NOT NULL,
+                    next TEXT NOT NULL,
+                    weight REAL NOT NULL,
+                    PRIMARY KEY (song, next)
+                );
+                INSERT INTO associations VALUES ('a.flac', 'b.flac', 0.75);
+                ",
+            )
+            .unwrap();
+
+        let mut db = SqliteDatabase::open(&path, None).unwrap();
+
+        let mut rows = Vec::new();
+        db.for_each_association(|assoc| rows.push(assoc)).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].updated > 0);
+        assert_eq!(
+            rows[0],
+            Association {
+                song: "a.flac".into(),
+                next: "b.flac".into(),
+                successes: 0.75,
+                failures: 0.25,
+                updated: rows[0].updated,
+            },
+        );
+
+        // Every later table was created
+        let stats = db.stats().unwrap();
+        assert_eq!(
+            stats,
+            StorageStats {
+                associations: 1,
+                tag_associations: 0,
+                songs: 0,
+                bytes: stats.bytes,
+            },
+        );
+        assert_eq!(db.setting("recommend").unwrap(), None);
+
+        let conn = SqliteConnection::establish(path.to_str().unwrap()).unwrap();
+        let versions = schema_version::table
+            .select(schema_version::version)
+            .order(schema_version::version)
+            .load::<i32>(&conn)
+            .unwrap();
+
+        assert_eq!(versions, (1..MIGRATIONS.len() as i32 + 1).collect::<Vec<_>>());
+
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -18,10 +18,33 @@
  * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+// diesel 1.x macros generate impls that newer compilers consider non-local
+#[allow(non_local_definitions)]
 mod models;
+#[allow(non_local_definitions)]
 mod schema;
+mod migrations;
+mod null;
+mod sqlite;
+
+pub use self::models::{Association, SongEntry, TagAssociation};
+pub use self::sqlite::SqliteDatabase;
 
 use StdResult;
+use identity::SongIndex;
+use interner::Interner;
+use markov::{Chain, Edge};
+use std::collections::{HashMap, HashSet};
+use tags::{Tag, TagChains};
+
+/// How much is kept in storage.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    pub associations: u64,
+    pub tag_associations: u64,
+    pub songs: u64,
+    pub bytes: u64,
+}
 
 pub trait Database {
     type Error;
@@ -30,8 +53,129 @@ pub trait Database {
         &mut self,
         this: &str,
         next: &str,
-        diff: i32,
+        diff: f32,
     ) -> StdResult<(), Self::Error>;
 
+    #[allow(dead_code)]
     fn clear(&mut self, this: &str) -> StdResult<(), Self::Error>;
+
+    /// Calls `f` on each stored association, without requiring
+    /// that the whole table be read into memory at once.
+    fn for_each_association<F>(&mut self, f: F) -> StdResult<(), Self::Error>
+    where
+        F: FnMut(Association);
+
+    #[allow(dead_code)]
+    fn successors(&mut self, this: &str) -> StdResult<Vec<Association>, Self::Error>;
+
+    /// Atomically replaces all outgoing associations of `this`.
+    #[allow(dead_code)]
+    fn replace_successors(
+        &mut self,
+        this: &str,
+        successors: &[(&str, Edge)],
+    ) -> StdResult<(), Self::Error>;
+
+    fn modify_tag_weight(
+        &mut self,
+        tag: &str,
+        this: &str,
+        next: &str,
+        diff: f32,
+    ) -> StdResult<(), Self::Error>;
+
+    fn for_each_tag_association<F>(&mut self, f: F) -> StdResult<(), Self::Error>
+    where
+        F: FnMut(TagAssociation);
+
+    /// Renames songs wherever they appear in associations, merging
+    /// the evidence of any which end up with the same key.
+    fn rename_songs(&mut self, renames: &HashMap<String, String>) -> StdResult<(), Self::Error>;
+
+    /// Removes every association involving any of `songs`,
+    /// whether in its context or as its target.
+    fn remove_songs(&mut self, songs: &HashSet<String>) -> StdResult<(), Self::Error>;
+
+    fn for_each_song<F>(&mut self, f: F) -> StdResult<(), Self::Error>
+    where
+        F: FnMut(SongEntry);
+
+    /// Atomically replaces the stored index of songs.
+    fn replace_songs(&mut self, songs: &SongIndex) -> StdResult<(), Self::Error>;
+
+    /// Looks up a stored setting, if it was ever set.
+    fn setting(&mut self, name: &str) -> StdResult<Option<String>, Self::Error>;
+
+    /// Stores a setting, replacing any previous value.
+    fn set_setting(&mut self, name: &str, value: &str) -> StdResult<(), Self::Error>;
+
+    /// Counts what is stored, and how much space it takes.
+    fn stats(&mut self) -> StdResult<StorageStats, Self::Error>;
+}
+
+/// Contexts of several songs are stored with each song on its own line.
+/// This is unambiguous since mpd's protocol is line-based, so no URI
+/// can contain a newline.
+pub fn encode_context(context: &[String]) -> String {
+    context.join("\n")
+}
+
+pub fn decode_context(context: &str) -> Vec<String> {
+    context.split('\n').map(String::from).collect()
+}
+
+/// Builds a chain of the given order from storage. Associations with
+/// longer contexts than the order are left in storage, but not loaded.
+///
+/// Songs are interned as they are read, so the chain stores them as IDs.
+pub fn load_chain<D: Database>(
+    database: &mut D,
+    interner: &mut Interner,
+    order: usize,
+    half_life: Option<f64>,
+) -> StdResult<Chain<u32>, D::Error> {
+    let mut chain = Chain::new(order).with_half_life(half_life);
+
+    database.for_each_association(|assoc| {
+        if assoc.song.split('\n').count() > order {
+            return;
+        }
+
+        let context = assoc.song
+            .split('\n')
+            .map(|song| interner.intern(song))
+            .collect();
+
+        let next = interner.intern(&assoc.next);
+        chain.set_weight(context, next, assoc.edge());
+    })?;
+
+    Ok(chain)
+}
+
+pub fn load_tag_chains<D: Database>(
+    database: &mut D,
+    tags: &[Tag],
+    half_life: Option<f64>,
+) -> StdResult<TagChains, D::Error> {
+    let mut chains = TagChains::new(tags, half_life);
+
+    database.for_each_tag_association(|assoc| {
+        let chain = Tag::from_str(&assoc.tag).and_then(|tag| chains.get_mut(tag));
+        if let Some(chain) = chain {
+            let edge = assoc.edge();
+            chain.set_weight(vec![assoc.value], assoc.next, edge);
+        }
+    })?;
+
+    Ok(chains)
+}
+
+pub fn load_songs<D: Database>(database: &mut D) -> StdResult<SongIndex, D::Error> {
+    let mut songs = SongIndex::new();
+    database.for_each_song(|entry| {
+        let (id, info) = entry.into_info();
+        songs.insert(id, info);
+    })?;
+    Ok(songs)
 }
@@ -18,13 +18,28 @@
  * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use identity::SongInfo;
+use markov::Edge;
 use super::schema::*;
 
 #[derive(Queryable, Debug, Clone, PartialEq)]
 pub struct Association {
     pub song: String,
     pub next: String,
-    pub weight: f32,
+    pub successes: f32,
+    pub failures: f32,
+    pub updated: i64,
+}
+
+impl Association {
+    #[inline]
+    pub fn edge(&self) -> Edge {
+        Edge {
+            successes: self.successes,
+            failures: self.failures,
+            updated: self.updated,
+        }
+    }
 }
 
 #[derive(Insertable, Debug, Copy, Clone, PartialEq)]
@@ -32,5 +47,98 @@ pub struct Association {
 pub struct NewAssociation<'a> {
     pub song: &'a str,
     pub next: &'a str,
-    pub weight: f32,
+    pub successes: f32,
+    pub failures: f32,
+    pub updated: i64,
+}
+
+#[derive(Queryable, Debug, Clone, PartialEq)]
+pub struct TagAssociation {
+    pub tag: String,
+    pub value: String,
+    pub next: String,
+    pub successes: f32,
+    pub failures: f32,
+    pub updated: i64,
+}
+
+impl TagAssociation {
+    #[inline]
+    pub fn edge(&self) -> Edge {
+        Edge {
+            successes: self.successes,
+            failures: self.failures,
+            updated: self.updated,
+        }
+    }
+}
+
+#[derive(Insertable, Debug, Copy, Clone, PartialEq)]
+#[table_name = "tag_associations"]
+pub struct NewTagAssociation<'a> {
+    pub tag: &'a str,
+    pub value: &'a str,
+    pub next: &'a str,
+    pub successes: f32,
+    pub failures: f32,
+    pub updated: i64,
+}
+
+#[derive(Queryable, Debug, Clone, PartialEq)]
+pub struct SongEntry {
+    pub id: String,
+    pub uri: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<i64>,
+    pub missing: bool,
+}
+
+impl SongEntry {
+    pub fn into_info(self) -> (String, SongInfo) {
+        let info = SongInfo {
+            uri: self.uri,
+            artist: self.artist,
+            title: self.title,
+            album: self.album,
+            duration: self.duration.map(|secs| secs as u64),
+            missing: self.missing,
+        };
+
+        (self.id, info)
+    }
+}
+
+#[derive(Insertable, Debug, Copy, Clone, PartialEq)]
+#[table_name = "songs"]
+pub struct NewSongEntry<'a> {
+    pub id: &'a str,
+    pub uri: &'a str,
+    pub artist: Option<&'a str>,
+    pub title: Option<&'a str>,
+    pub album: Option<&'a str>,
+    pub duration: Option<i64>,
+    pub missing: bool,
+}
+
+impl<'a> NewSongEntry<'a> {
+    pub fn new(id: &'a str, info: &'a SongInfo) -> Self {
+        NewSongEntry {
+            id,
+            uri: &info.uri,
+            artist: info.artist.as_deref(),
+            title: info.title.as_deref(),
+            album: info.album.as_deref(),
+            duration: info.duration.map(|secs| secs as i64),
+            missing: info.missing,
+        }
+    }
+}
+
+#[derive(Insertable, Debug, Copy, Clone, PartialEq)]
+#[table_name = "settings"]
+pub struct NewSetting<'a> {
+    pub name: &'a str,
+    pub value: &'a str,
 }
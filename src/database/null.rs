@@ -19,7 +19,10 @@
  */
 
 use StdResult;
-use super::Database;
+use identity::SongIndex;
+use markov::Edge;
+use std::collections::{HashMap, HashSet};
+use super::{Association, Database, SongEntry, StorageStats, TagAssociation};
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub struct NullDatabase;
@@ -31,7 +34,7 @@ impl Database for NullDatabase {
         &mut self,
         _this: &str,
         _next: &str,
-        _diff: i32,
+        _diff: f32,
     ) -> StdResult<(), ()> {
         Ok(())
     }
@@ -39,4 +42,71 @@ impl Database for NullDatabase {
     fn clear(&mut self, _this: &str) -> StdResult<(), ()> {
         Ok(())
     }
+
+    fn for_each_association<F>(&mut self, _f: F) -> StdResult<(), ()>
+    where
+        F: FnMut(Association),
+    {
+        Ok(())
+    }
+
+    fn successors(&mut self, _this: &str) -> StdResult<Vec<Association>, ()> {
+        Ok(Vec::new())
+    }
+
+    fn replace_successors(
+        &mut self,
+        _this: &str,
+        _successors: &[(&str, Edge)],
+    ) -> StdResult<(), ()> {
+        Ok(())
+    }
+
+    fn modify_tag_weight(
+        &mut self,
+        _tag: &str,
+        _this: &str,
+        _next: &str,
+        _diff: f32,
+    ) -> StdResult<(), ()> {
+        Ok(())
+    }
+
+    fn for_each_tag_association<F>(&mut self, _f: F) -> StdResult<(), ()>
+    where
+        F: FnMut(TagAssociation),
+    {
+        Ok(())
+    }
+
+    fn rename_songs(&mut self, _renames: &HashMap<String, String>) -> StdResult<(), ()> {
+        Ok(())
+    }
+
+    fn remove_songs(&mut self, _songs: &HashSet<String>) -> StdResult<(), ()> {
+        Ok(())
+    }
+
+    fn for_each_song<F>(&mut self, _f: F) -> StdResult<(), ()>
+    where
+        F: FnMut(SongEntry),
+    {
+        Ok(())
+    }
+
+    fn replace_songs(&mut self, _songs: &SongIndex) -> StdResult<(), ()> {
+        Ok(())
+    }
+
+    fn setting(&mut self, _name: &str) -> StdResult<Option<String>, ()> {
+        Ok(None)
+    }
+
+    fn set_setting(&mut self, _name: &str, _value: &str) -> StdResult<(), ()> {
+        Ok(())
+    }
+
+    fn stats(&mut self) -> StdResult<StorageStats, ()> {
+        Ok(StorageStats::default())
+    }
 }
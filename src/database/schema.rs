@@ -22,6 +22,44 @@ table! {
     associations (song, next) {
         song -> Text,
         next -> Text,
-        weight -> Float,
+        successes -> Float,
+        failures -> Float,
+        updated -> BigInt,
+    }
+}
+
+table! {
+    tag_associations (tag, value, next) {
+        tag -> Text,
+        value -> Text,
+        next -> Text,
+        successes -> Float,
+        failures -> Float,
+        updated -> BigInt,
+    }
+}
+
+table! {
+    songs (id) {
+        id -> Text,
+        uri -> Text,
+        artist -> Nullable<Text>,
+        title -> Nullable<Text>,
+        album -> Nullable<Text>,
+        duration -> Nullable<BigInt>,
+        missing -> Bool,
+    }
+}
+
+table! {
+    settings (name) {
+        name -> Text,
+        value -> Text,
+    }
+}
+
+table! {
+    schema_version (version) {
+        version -> Integer,
     }
 }
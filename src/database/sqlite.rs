@@ -1,5 +1,5 @@
 /*
- * database/handle.rs
+ * database/sqlite.rs
  *
  * markov-music - A music player that uses Markov chains to choose songs
  * Copyright (c) 2017-2018 Ammon Smith
@@ -18,20 +18,50 @@
  * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use {diesel, Error, Result, StdResult};
+use {diesel, Error, Result};
+use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
-use super::Database;
+use std::fmt::{self, Debug};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use super::{decode_context, encode_context, migrations, Database, StorageStats};
 use super::models::*;
 use super::schema::*;
+use identity::SongIndex;
+use markov::Edge;
+use utils;
+
+/// How many rows to fetch at a time when iterating over the whole table.
+const BATCH_SIZE: i64 = 1024;
+
+/// How many songs to insert per statement, keeping within
+/// SQLite's limit on the number of bound parameters.
+const SONG_BATCH_SIZE: usize = 128;
 
 pub struct SqliteDatabase {
+    path: PathBuf,
+    half_life: Option<f64>,
     conn: SqliteConnection,
 }
 
 impl SqliteDatabase {
-    pub fn new<S: AsRef<str>>(url: S) -> Result<Self> {
-        let conn = SqliteConnection::establish(url.as_ref())?;
-        Ok(SqliteDatabase { conn: conn })
+    pub fn open<P: AsRef<Path>>(path: P, half_life: Option<f64>) -> Result<Self> {
+        let path = path.as_ref();
+        let url = match path.to_str() {
+            Some(url) => url,
+            None => return Err(Error::StaticMsg("Storage file path is not valid UTF-8")),
+        };
+
+        let conn = SqliteConnection::establish(url)?;
+        migrations::run(&conn)?;
+
+        Ok(SqliteDatabase {
+            path: path.to_path_buf(),
+            half_life,
+            conn,
+        })
     }
 }
 
@@ -42,25 +72,29 @@ impl Database for SqliteDatabase {
         &mut self,
         song: &str,
         next: &str,
-        diff: i32,
+        diff: f32,
     ) -> Result<()> {
         self.conn.transaction::<(), Error, _>(|| {
-            use self::associations::dsl;
-
             let row = associations::table
                 .find((song, next))
                 .first::<Association>(&self.conn)
                 .optional()?;
 
-            let weight = row.map(|assoc| assoc.weight).unwrap_or(0) + diff;
+            let now = utils::now();
+            let mut edge = row.map(|assoc| assoc.edge()).unwrap_or_else(|| Edge::new(now));
+            edge.decay(now, self.half_life);
+            edge.observe(diff);
+
             let new_assoc = NewAssociation {
-                song: song,
-                next: next,
-                weight: weight,
+                song,
+                next,
+                successes: edge.successes,
+                failures: edge.failures,
+                updated: edge.updated,
             };
 
-            diesel::replace_into(starters::table)
-                .values(&[new_assoc])
+            diesel::replace_into(associations::table)
+                .values(&new_assoc)
                 .execute(&self.conn)?;
 
             Ok(())
@@ -77,4 +111,364 @@ impl Database for SqliteDatabase {
             Ok(())
         })
     }
+
+    fn for_each_association<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(Association),
+    {
+        use self::associations::dsl;
+
+        let mut last: Option<(String, String)> = None;
+        loop {
+            let mut query = associations::table
+                .order((dsl::song, dsl::next))
+                .limit(BATCH_SIZE)
+                .into_boxed();
+
+            if let Some((ref song, ref next)) = last {
+                query = query.filter(
+                    dsl::song.gt(song.as_str())
+                        .or(dsl::song.eq(song.as_str()).and(dsl::next.gt(next.as_str()))),
+                );
+            }
+
+            let rows = query.load::<Association>(&self.conn)?;
+            let done = (rows.len() as i64) < BATCH_SIZE;
+            last = rows.last().map(|assoc| (assoc.song.clone(), assoc.next.clone()));
+
+            for row in rows {
+                f(row);
+            }
+
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    fn successors(&mut self, song: &str) -> Result<Vec<Association>> {
+        use self::associations::dsl;
+
+        let rows = associations::table
+            .filter(dsl::song.eq(song))
+            .load::<Association>(&self.conn)?;
+
+        Ok(rows)
+    }
+
+    fn replace_successors(&mut self, song: &str, successors: &[(&str, Edge)]) -> Result<()> {
+        self.conn.transaction::<(), Error, _>(|| {
+            use self::associations::dsl;
+
+            diesel::delete(associations::table.filter(dsl::song.eq(song)))
+                .execute(&self.conn)?;
+
+            let rows = successors
+                .iter()
+                .map(|&(next, edge)| NewAssociation {
+                    song,
+                    next,
+                    successes: edge.successes,
+                    failures: edge.failures,
+                    updated: edge.updated,
+                })
+                .collect::<Vec<_>>();
+
+            diesel::insert_into(associations::table)
+                .values(&rows)
+                .execute(&self.conn)?;
+
+            Ok(())
+        })
+    }
+
+    fn modify_tag_weight(
+        &mut self,
+        tag: &str,
+        value: &str,
+        next: &str,
+        diff: f32,
+    ) -> Result<()> {
+        self.conn.transaction::<(), Error, _>(|| {
+            let row = tag_associations::table
+                .find((tag, value, next))
+                .first::<TagAssociation>(&self.conn)
+                .optional()?;
+
+            let now = utils::now();
+            let mut edge = row.map(|assoc| assoc.edge()).unwrap_or_else(|| Edge::new(now));
+            edge.decay(now, self.half_life);
+            edge.observe(diff);
+
+            let new_assoc = NewTagAssociation {
+                tag,
+                value,
+                next,
+                successes: edge.successes,
+                failures: edge.failures,
+                updated: edge.updated,
+            };
+
+            diesel::replace_into(tag_associations::table)
+                .values(&new_assoc)
+                .execute(&self.conn)?;
+
+            Ok(())
+        })
+    }
+
+    fn for_each_tag_association<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnMut(TagAssociation),
+    {
+        // Far fewer distinct tag values than songs, so no batching is needed here
+        tag_associations::table
+            .load::<TagAssociation>(&self.conn)?
+            .into_iter()
+            .for_each(f);
+
+        Ok(())
+    }
+
+    fn rename_songs(&mut self, renames: &HashMap<String, String>) -> Result<()> {
+        let rename = |item: &str| match renames.get(item) {
+            Some(new) => new.clone(),
+            None => item.to_owned(),
+        };
+
+        // Collect the renamed rows first, merging any which collide
+        let mut stale = Vec::new();
+        let mut renamed = HashMap::<(String, String), Edge>::new();
+        let half_life = self.half_life;
+
+        self.for_each_association(|assoc| {
+            let context = decode_context(&assoc.song);
+            if !context.iter().chain(Some(&assoc.next)).any(|item| renames.contains_key(item)) {
+                return;
+            }
+
+            let context = context.iter().map(|item| rename(item)).collect::<Vec<_>>();
+            let key = (encode_context(&context), rename(&assoc.next));
+            let edge = assoc.edge();
+
+            match renamed.entry(key) {
+                Entry::Occupied(mut entry) => entry.get_mut().merge(edge, half_life),
+                Entry::Vacant(entry) => {
+                    entry.insert(edge);
+                }
+            }
+
+            stale.push((assoc.song, assoc.next));
+        })?;
+
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        debug!("Renaming {} associations", stale.len());
+        self.conn.transaction::<(), Error, _>(|| {
+            for (song, next) in &stale {
+                diesel::delete(associations::table.find((song, next)))
+                    .execute(&self.conn)?;
+            }
+
+            for ((song, next), mut edge) in renamed {
+                let existing = associations::table
+                    .find((&song, &next))
+                    .first::<Association>(&self.conn)
+                    .optional()?;
+
+                if let Some(existing) = existing {
+                    edge.merge(existing.edge(), half_life);
+                }
+
+                let new_assoc = NewAssociation {
+                    song: &song,
+                    next: &next,
+                    successes: edge.successes,
+                    failures: edge.failures,
+                    updated: edge.updated,
+                };
+
+                diesel::replace_into(associations::table)
+                    .values(&new_assoc)
+                    .execute(&self.conn)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn remove_songs(&mut self, songs: &HashSet<String>) -> Result<()> {
+        let mut stale = Vec::new();
+        self.for_each_association(|assoc| {
+            let involved = decode_context(&assoc.song).iter().any(|item| songs.contains(item))
+                || songs.contains(&assoc.next);
+
+            if involved {
+                stale.push((assoc.song, assoc.next));
+            }
+        })?;
+
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        debug!("Removing {} associations", stale.len());
+        self.conn.transaction::<(), Error, _>(|| {
+            for (song, next) in &stale {
+                diesel::delete(associations::table.find((song, next)))
+                    .execute(&self.conn)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn for_each_song<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnMut(SongEntry),
+    {
+        songs::table
+            .load::<SongEntry>(&self.conn)?
+            .into_iter()
+            .for_each(f);
+
+        Ok(())
+    }
+
+    fn replace_songs(&mut self, index: &SongIndex) -> Result<()> {
+        self.conn.transaction::<(), Error, _>(|| {
+            diesel::delete(songs::table).execute(&self.conn)?;
+
+            let rows = index
+                .iter()
+                .map(|(id, info)| NewSongEntry::new(id, info))
+                .collect::<Vec<_>>();
+
+            for chunk in rows.chunks(SONG_BATCH_SIZE) {
+                diesel::insert_into(songs::table)
+                    .values(chunk)
+                    .execute(&self.conn)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn setting(&mut self, name: &str) -> Result<Option<String>> {
+        let value = settings::table
+            .find(name)
+            .select(settings::value)
+            .first::<String>(&self.conn)
+            .optional()?;
+
+        Ok(value)
+    }
+
+    fn set_setting(&mut self, name: &str, value: &str) -> Result<()> {
+        diesel::replace_into(settings::table)
+            .values(&NewSetting { name, value })
+            .execute(&self.conn)?;
+
+        Ok(())
+    }
+
+    fn stats(&mut self) -> Result<StorageStats> {
+        let associations = associations::table.count().get_result::<i64>(&self.conn)?;
+        let tag_associations = tag_associations::table.count().get_result::<i64>(&self.conn)?;
+        let songs = songs::table.count().get_result::<i64>(&self.conn)?;
+        let bytes = fs::metadata(&self.path)?.len();
+
+        Ok(StorageStats {
+            associations: associations as u64,
+            tag_associations: tag_associations as u64,
+            songs: songs as u64,
+            bytes,
+        })
+    }
+}
+
+impl Debug for SqliteDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SqliteDatabase")
+         .field("path", &self.path)
+         .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> SqliteDatabase {
+        SqliteDatabase::open(":memory:", None).unwrap()
+    }
+
+    fn edge(successes: f32) -> Edge {
+        Edge {
+            successes,
+            failures: 1.0,
+            updated: 0,
+        }
+    }
+
+    fn successors(db: &mut SqliteDatabase, song: &str) -> Vec<(String, Edge)> {
+        let mut rows = db.successors(song)
+            .unwrap()
+            .into_iter()
+            .map(|assoc| (assoc.next.clone(), assoc.edge()))
+            .collect::<Vec<_>>();
+
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+        rows
+    }
+
+    #[test]
+    fn association_paging() {
+        let mut db = open();
+        let nexts = (0..600).map(|n| format!("{:03}.flac", n)).collect::<Vec<_>>();
+
+        // More rows than fit in a batch, with the first batch ending partway through a song
+        let mut expected = Vec::new();
+        for song in &["a.flac", "b.flac", "c.flac"] {
+            let successors = nexts
+                .iter()
+                .map(|next| (next.as_str(), edge(1.0)))
+                .collect::<Vec<_>>();
+
+            db.replace_successors(song, &successors).unwrap();
+            expected.extend(nexts.iter().map(|next| (song.to_string(), next.clone())));
+        }
+
+        assert!(expected.len() > BATCH_SIZE as usize);
+
+        let mut rows = Vec::new();
+        db.for_each_association(|assoc| rows.push((assoc.song, assoc.next)))
+            .unwrap();
+
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn replace_successors() {
+        let mut db = open();
+        db.replace_successors("b.flac", &[("c.flac", edge(3.0))]).unwrap();
+        db.replace_successors("a.flac", &[("b.flac", edge(1.0)), ("c.flac", edge(2.0))])
+            .unwrap();
+
+        db.replace_successors("a.flac", &[("c.flac", edge(4.0)), ("d.flac", edge(5.0))])
+            .unwrap();
+
+        assert_eq!(
+            successors(&mut db, "a.flac"),
+            vec![("c.flac".into(), edge(4.0)), ("d.flac".into(), edge(5.0))],
+        );
+
+        db.replace_successors("a.flac", &[]).unwrap();
+        assert_eq!(successors(&mut db, "a.flac"), vec![]);
+
+        // Other songs' successors are left alone
+        assert_eq!(successors(&mut db, "b.flac"), vec![("c.flac".into(), edge(3.0))]);
+    }
 }
@@ -18,6 +18,7 @@
  * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use diesel::{self, ConnectionError};
 use mpd::error as mpd;
 use self::Error::*;
 use std::{fmt, num, io};
@@ -37,24 +38,31 @@ pub enum Error {
     MpdParse(mpd::ParseError),
     MpdProto(mpd::ProtoError),
     MpdServer(mpd::ServerError),
+    Database(diesel::result::Error),
+    DatabaseConnection(ConnectionError),
 }
 
-impl StdError for Error {
-    fn description(&self) -> &str {
+impl Error {
+    /// A short, stable name for the kind of error,
+    /// for clients of the control socket.
+    pub fn code(&self) -> &'static str {
         match *self {
-            StaticMsg(s) => s,
-            Msg(ref s) => s,
-            Io(ref e) => e.description(),
-            IntParse(ref e) => e.description(),
-            Utf8(ref e) => e.description(),
-            TomlDe(ref e) => e.description(),
-            MpdParse(ref e) => e.description(),
-            MpdProto(ref e) => e.description(),
-            MpdServer(ref e) => e.description(),
+            StaticMsg(_) | Msg(_) => "failed",
+            Io(_) => "io",
+            IntParse(_) => "int-parse",
+            Utf8(_) => "utf8",
+            TomlDe(_) => "config",
+            MpdParse(_) => "mpd-parse",
+            MpdProto(_) => "mpd-protocol",
+            MpdServer(_) => "mpd-server",
+            Database(_) => "database",
+            DatabaseConnection(_) => "database-connection",
         }
     }
+}
 
-    fn cause(&self) -> Option<&StdError> {
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match *self {
             StaticMsg(_) | Msg(_) => None,
             Io(ref e) => Some(e),
@@ -64,13 +72,27 @@ impl StdError for Error {
             MpdParse(ref e) => Some(e),
             MpdProto(ref e) => Some(e),
             MpdServer(ref e) => Some(e),
+            Database(ref e) => Some(e),
+            DatabaseConnection(ref e) => Some(e),
         }
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", StdError::description(self))
+        match *self {
+            StaticMsg(s) => write!(f, "{}", s),
+            Msg(ref s) => write!(f, "{}", s),
+            Io(ref e) => write!(f, "{}", e),
+            IntParse(ref e) => write!(f, "{}", e),
+            Utf8(ref e) => write!(f, "{}", e),
+            TomlDe(ref e) => write!(f, "{}", e),
+            MpdParse(ref e) => write!(f, "{}", e),
+            MpdProto(ref e) => write!(f, "{}", e),
+            MpdServer(ref e) => write!(f, "{}", e),
+            Database(ref e) => write!(f, "{}", e),
+            DatabaseConnection(ref e) => write!(f, "{}", e),
+        }
     }
 }
 
@@ -105,6 +127,18 @@ impl From<toml::de::Error> for Error {
     }
 }
 
+impl From<diesel::result::Error> for Error {
+    fn from(error: diesel::result::Error) -> Self {
+        Error::Database(error)
+    }
+}
+
+impl From<ConnectionError> for Error {
+    fn from(error: ConnectionError) -> Self {
+        Error::DatabaseConnection(error)
+    }
+}
+
 impl From<mpd::Error> for Error {
     fn from(error: mpd::Error) -> Self {
         use self::mpd::Error::*;
@@ -0,0 +1,162 @@
+/*
+ * events.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use player::Listen;
+use std::sync::Mutex;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+/// How many events may be waiting for a subscriber before
+/// any more are dropped.
+const BUFFER_SIZE: usize = 256;
+
+/// Something the daemon did, as pushed to subscribers.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// A song started playing.
+    SongStarted { song: String },
+
+    /// A transition was learned from how a song was listened to.
+    Learned {
+        from: Option<String>,
+        to: String,
+        listen: Listen,
+        delta: f32,
+    },
+
+    /// Songs were added to the queue.
+    Enqueued { songs: Vec<String> },
+
+    /// Queue management was turned on or off.
+    Recommend { enabled: bool },
+
+    /// Something went wrong.
+    Error { message: String },
+
+    /// The subscriber fell behind, and missed this many events.
+    Dropped { count: usize },
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    sender: SyncSender<Event>,
+    dropped: usize,
+}
+
+impl Subscriber {
+    /// Queues an event without blocking, dropping it if the subscriber
+    /// is too far behind. Returns `false` once the subscriber is gone.
+    fn send(&mut self, event: &Event) -> bool {
+        // Tell the subscriber what it missed before anything newer
+        if self.dropped > 0 {
+            match self.sender.try_send(Event::Dropped { count: self.dropped }) {
+                Ok(()) => self.dropped = 0,
+                Err(TrySendError::Full(_)) => {
+                    self.dropped += 1;
+                    return true;
+                },
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+
+        match self.sender.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                true
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+/// Hands out events to everyone subscribed to them.
+///
+/// Each subscriber has its own bounded buffer, so publishing never
+/// waits on a slow subscriber. Events which don't fit are dropped,
+/// and the subscriber is sent how many it missed once it catches up.
+#[derive(Debug, Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    /// Returns a receiver for every event published from now on.
+    /// Dropping it ends the subscription.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = sync_channel(BUFFER_SIZE);
+
+        self.subscribers
+            .lock()
+            .expect("Subscribers lock poisoned")
+            .push(Subscriber { sender, dropped: 0 });
+
+        receiver
+    }
+
+    pub fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .expect("Subscribers lock poisoned")
+            .retain_mut(|subscriber| subscriber.send(&event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn started(n: usize) -> Event {
+        Event::SongStarted {
+            song: format!("{}.flac", n),
+        }
+    }
+
+    #[test]
+    fn slow_subscriber() {
+        let bus = EventBus::new();
+        let events = bus.subscribe();
+
+        // Nobody is reading, so this must not block
+        for n in 0..BUFFER_SIZE + 10 {
+            bus.publish(started(n));
+        }
+
+        let received = events.try_iter().collect::<Vec<_>>();
+        assert_eq!(received, (0..BUFFER_SIZE).map(started).collect::<Vec<_>>());
+
+        bus.publish(started(0));
+        assert_eq!(events.try_recv(), Ok(Event::Dropped { count: 10 }));
+        assert_eq!(events.try_recv(), Ok(started(0)));
+    }
+
+    #[test]
+    fn closed_subscriber() {
+        let bus = EventBus::new();
+        drop(bus.subscribe());
+
+        bus.publish(started(0));
+        assert!(bus.subscribers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+/*
+ * identity.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use mpd::Song;
+use std::collections::HashMap;
+
+const MUSICBRAINZ_TAG: &str = "MUSICBRAINZ_TRACKID";
+
+/// Returns a stable identifier for a song, which the chain learns
+/// transitions between. Unlike the song's URI, it survives the file
+/// being renamed or moved, as long as its tags stay the same.
+///
+/// In order of preference, this is the MusicBrainz track ID, a
+/// fingerprint of the artist, title and duration, and finally the URI.
+pub fn identify(song: &Song) -> String {
+    let mbid = song.tags
+        .iter()
+        .find(|(name, _)| name == MUSICBRAINZ_TAG)
+        .map(|(_, value)| value.trim())
+        .filter(|value| !value.is_empty());
+
+    if let Some(mbid) = mbid {
+        return format!("mbid:{}", mbid.to_lowercase());
+    }
+
+    if let (Some(artist), Some(title), Some(duration)) = (&song.artist, &song.title, duration(song)) {
+        return format!("tags:{}\t{}\t{}", normalize(artist), normalize(title), duration);
+    }
+
+    format!("uri:{}", song.file)
+}
+
+/// The song's duration in whole seconds, if known.
+pub fn duration(song: &Song) -> Option<u64> {
+    if let Some(duration) = song.duration {
+        return Some(duration.as_secs());
+    }
+
+    song.tags
+        .iter()
+        .find(|(name, _)| name == "duration")
+        .and_then(|(_, value)| value.parse::<f64>().ok())
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(|secs| secs.round() as u64)
+}
+
+/// Lowercases and collapses whitespace, so that trivial differences
+/// in tagging do not change a song's identity.
+pub fn normalize(value: &str) -> String {
+    value
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// What is remembered about a song in the library, so that it can
+/// still be recognized if its file is moved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SongInfo {
+    pub uri: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<u64>,
+
+    /// Whether the song has vanished from the library, but is kept
+    /// in case it can be matched to a new file later.
+    pub missing: bool,
+}
+
+impl SongInfo {
+    pub fn from_song(song: &Song) -> Self {
+        let album = song.tags
+            .iter()
+            .find(|(name, _)| name == "Album")
+            .map(|(_, value)| value.clone());
+
+        SongInfo {
+            uri: song.file.clone(),
+            artist: song.artist.clone(),
+            title: song.title.clone(),
+            album,
+            duration: duration(song),
+            missing: false,
+        }
+    }
+}
+
+/// Maps song identities to their current URIs in the mpd library.
+#[derive(Debug, Clone, Default)]
+pub struct SongIndex {
+    songs: HashMap<String, SongInfo>,
+}
+
+impl SongIndex {
+    pub fn new() -> Self {
+        SongIndex::default()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.songs.is_empty()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.songs.len()
+    }
+
+    pub fn insert(&mut self, id: String, info: SongInfo) {
+        self.songs.insert(id, info);
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<SongInfo> {
+        self.songs.remove(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&SongInfo> {
+        self.songs.get(id)
+    }
+
+    /// The song's current URI, unless it is missing from the library.
+    pub fn uri(&self, id: &str) -> Option<&str> {
+        self.songs
+            .get(id)
+            .filter(|info| !info.missing)
+            .map(|info| info.uri.as_str())
+    }
+
+    /// The identity of the song currently at `uri`, if any.
+    pub fn find_uri(&self, uri: &str) -> Option<&str> {
+        self.songs
+            .iter()
+            .find(|&(_, info)| !info.missing && info.uri == uri)
+            .map(|(id, _)| id.as_str())
+    }
+
+    /// Builds an index of the given library songs. If several files have
+    /// the same identity, the one which sorts first is used.
+    pub fn from_songs(songs: &[Song]) -> Self {
+        let mut index = HashMap::with_capacity(songs.len());
+
+        for song in songs {
+            let info = index
+                .entry(identify(song))
+                .or_insert_with(|| SongInfo::from_song(song));
+
+            if song.file < info.uri {
+                *info = SongInfo::from_song(song);
+            }
+        }
+
+        SongIndex { songs: index }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &SongInfo)> {
+        self.songs.iter().map(|(id, info)| (id.as_str(), info))
+    }
+}
@@ -0,0 +1,65 @@
+/*
+ * interner.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Maps strings to compact numeric IDs and back, so that the chain
+/// can store each song as a `u32` rather than repeating its name in
+/// every transition. Each string is only allocated once.
+///
+/// IDs are never reused, and are only meaningful to the interner
+/// which assigned them.
+#[derive(Debug, Clone, Default)]
+pub struct Interner {
+    ids: HashMap<Arc<str>, u32>,
+    names: Vec<Arc<str>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Interner::default()
+    }
+
+    /// Returns the ID of `name`, assigning a new one if needed.
+    pub fn intern(&mut self, name: &str) -> u32 {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+
+        assert!(self.names.len() < u32::MAX as usize, "Interner is full");
+
+        let id = self.names.len() as u32;
+        let name: Arc<str> = Arc::from(name);
+        self.ids.insert(Arc::clone(&name), id);
+        self.names.push(name);
+        id
+    }
+
+    /// Returns the ID of `name`, if it has one.
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.ids.get(name).cloned()
+    }
+
+    /// Returns the name of an ID assigned by this interner.
+    pub fn resolve(&self, id: u32) -> &str {
+        &self.names[id as usize]
+    }
+}
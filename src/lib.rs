@@ -0,0 +1,74 @@
+/*
+ * lib.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+#![deny(missing_debug_implementations)]
+
+extern crate clap;
+
+#[macro_use]
+extern crate diesel;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate log;
+extern crate mpd;
+extern crate rand;
+
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+extern crate simple_logging;
+extern crate toml;
+
+mod choose;
+pub mod config;
+pub mod context;
+mod database;
+mod error;
+mod events;
+mod identity;
+mod interner;
+pub mod logging;
+mod markov;
+mod model;
+mod player;
+mod queue;
+mod reconcile;
+mod settings;
+pub mod socket;
+mod tags;
+mod utils;
+
+pub use error::{Error, StdError};
+
+/// Internals used by the benchmarks in `tests/bench.rs`,
+/// and to test the client against the daemon.
+#[doc(hidden)]
+pub mod internals {
+    pub use choose::{weighted_index, Choice, Proportional, SelectionStrategy};
+    pub use interner::Interner;
+    pub use markov::{Chain, Edge};
+    pub use socket::split_args;
+}
+
+pub type StdResult<T, E> = std::result::Result<T, E>;
+pub type Result<T> = StdResult<T, Error>;
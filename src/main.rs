@@ -18,45 +18,17 @@
  * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-#![deny(missing_debug_implementations)]
-
-extern crate clap;
-
-#[macro_use]
-extern crate diesel;
-
-#[macro_use]
-extern crate lazy_static;
-
 #[macro_use]
 extern crate log;
-extern crate mpd;
-extern crate rand;
-
-#[macro_use]
-extern crate serde;
-extern crate simple_logging;
-extern crate toml;
-
-mod choose;
-mod config;
-mod context;
-mod database;
-mod error;
-mod logging;
-mod markov;
-mod player;
-mod socket;
-mod utils;
+extern crate markov_music;
 
-use config::{parse_args, Config};
-use context::Context;
+use markov_music::{logging, Result};
+use markov_music::config::{parse_args, Config};
+use markov_music::context::Context;
+use markov_music::socket::SocketServer;
+use std::borrow::Borrow;
 use std::process::exit;
-
-pub use error::{Error, StdError};
-
-pub type StdResult<T, E> = std::result::Result<T, E>;
-pub type Result<T> = StdResult<T, Error>;
+use std::thread;
 
 fn main() {
     let config = match parse_args() {
@@ -78,8 +50,17 @@ fn main() {
 fn main_loop(config: Config) -> Result<()> {
     let mut ctx = Context::new(config)?;
 
+    let socket = {
+        let config: &Config = ctx.borrow();
+        SocketServer::bind(config, ctx.settings(), ctx.model(), ctx.tracker(), ctx.events())?
+    };
+    thread::spawn(move || loop {
+        if let Err(e) = socket.wait() {
+            warn!("Error accepting socket connection: {}", e);
+        }
+    });
+
     loop {
         ctx.wait()?;
-        // TODO
     }
 }
@@ -18,94 +18,470 @@
  * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use choose::{random_key, roulette_wheel};
+use choose::{random_key, AliasTable, Choice, SelectionStrategy};
 use rand::Rng;
-use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::hash::Hash;
-use utils::sigmoid;
+use std::mem;
+use utils;
 
+/// Pseudo-counts of the uniform Beta(1, 1) prior every transition starts from.
+const PRIOR: f32 = 1.0;
+
+/// The evidence learned for a transition, as a Beta posterior over
+/// how likely the listener is to enjoy it. Positive feedback counts
+/// towards `successes` and negative feedback towards `failures`,
+/// so that probabilities are only derived when they are needed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Edge {
+    pub successes: f32,
+    pub failures: f32,
+    pub updated: i64,
+}
+
+impl Edge {
+    pub fn new(updated: i64) -> Self {
+        Edge {
+            successes: 0.0,
+            failures: 0.0,
+            updated,
+        }
+    }
+
+    /// Adds feedback to the evidence. Positive values are successes,
+    /// and negative values failures.
+    pub fn observe(&mut self, diff: f32) {
+        assert!(diff.is_finite());
+
+        if diff > 0.0 {
+            self.successes += diff;
+        } else {
+            self.failures -= diff;
+        }
+    }
+
+    /// Decays the evidence up to `now`, given its half-life in seconds.
+    pub fn decay(&mut self, now: i64, half_life: Option<f64>) {
+        let factor = decay(now - self.updated, half_life);
+
+        self.successes *= factor;
+        self.failures *= factor;
+        self.updated = now;
+    }
+
+    /// Combines the evidence of another edge into this one,
+    /// decaying both up to whichever was updated last.
+    pub fn merge(&mut self, mut other: Edge, half_life: Option<f64>) {
+        let now = self.updated.max(other.updated);
+        self.decay(now, half_life);
+        other.decay(now, half_life);
+
+        self.successes += other.successes;
+        self.failures += other.failures;
+    }
+
+    /// The posterior Beta distribution's parameters.
+    #[inline]
+    pub fn alpha_beta(&self) -> (f32, f32) {
+        (self.successes + PRIOR, self.failures + PRIOR)
+    }
+
+    /// The posterior mean, i.e. the estimated probability
+    /// the listener enjoys this transition.
+    pub fn mean(&self) -> f32 {
+        let (alpha, beta) = self.alpha_beta();
+        alpha / (alpha + beta)
+    }
+}
+
+/// The factor evidence has decayed by after `age` seconds,
+/// given its half-life in seconds.
+pub fn decay(age: i64, half_life: Option<f64>) -> f32 {
+    match half_life {
+        Some(half_life) if age > 0 => 0.5f64.powf(age as f64 / half_life) as f32,
+        _ => 1.0,
+    }
+}
+
+/// Returns the suffixes of `history` (oldest song first) which a chain
+/// of the given order uses as contexts, longest first.
+pub fn contexts<T>(history: &[T], order: usize) -> impl Iterator<Item = &[T]> {
+    let len = history.len();
+    let longest = order.min(len);
+
+    (1..=longest).rev().map(move |n| &history[len - n..])
+}
+
+/// How long an alias table stays valid, as a fraction of the half-life.
+/// Decay shifts the relative weights of edges updated at different
+/// times, so tables are rebuilt once they may have drifted by about 1%.
+const TABLE_LIFETIME: f64 = 0.015;
+
+/// An alias table over a context's successors, and when it was built.
+#[derive(Debug, Clone, PartialEq)]
+struct CachedTable {
+    table: AliasTable,
+    built: i64,
+}
+
+/// The transitions out of a single context, kept as parallel vectors
+/// sorted by target. Sampling walks the edges in a fixed order without
+/// any hashing, and lookups use binary search.
+///
+/// For proportional choices, an alias table is built the first time
+/// it is needed and kept until the edges change, so that even songs
+/// with thousands of successors are sampled in constant time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Successors<T> {
+    items: Vec<T>,
+    edges: Vec<Edge>,
+    table: RefCell<Option<CachedTable>>,
+}
+
+impl<T: Ord> Successors<T> {
+    fn new() -> Self {
+        Successors {
+            items: Vec::new(),
+            edges: Vec::new(),
+            table: RefCell::new(None),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    #[inline]
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    #[inline]
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    fn entry<F: FnOnce() -> Edge>(&mut self, item: T, default: F) -> &mut Edge {
+        *self.table.get_mut() = None;
+
+        let idx = match self.items.binary_search(&item) {
+            Ok(idx) => idx,
+            Err(idx) => {
+                self.items.insert(idx, item);
+                self.edges.insert(idx, default());
+                idx
+            }
+        };
+
+        &mut self.edges[idx]
+    }
+
+    fn retain<F: FnMut(&T) -> bool>(&mut self, mut keep: F) {
+        *self.table.get_mut() = None;
+
+        let items = mem::take(&mut self.items);
+        let edges = mem::take(&mut self.edges);
+        for (item, edge) in items.into_iter().zip(edges) {
+            if keep(&item) {
+                self.items.push(item);
+                self.edges.push(edge);
+            }
+        }
+    }
+
+    /// Samples a successor in proportion to `weight`, using the cached
+    /// alias table unless it is older than `lifetime` seconds.
+    fn sample<F>(&self, now: i64, lifetime: Option<i64>, weight: F, rng: &mut dyn Rng) -> Option<usize>
+    where F: Fn(&Edge) -> f32,
+    {
+        let mut cached = self.table.borrow_mut();
+        let stale = match *cached {
+            Some(ref cached) => lifetime.is_some_and(|lifetime| now - cached.built >= lifetime),
+            None => true,
+        };
+
+        if stale {
+            let weights = self.edges.iter().map(weight).collect::<Vec<_>>();
+            *cached = AliasTable::new(&weights).map(|table| CachedTable { table, built: now });
+        }
+
+        cached.as_ref().map(|cached| cached.table.sample(rng))
+    }
+}
+
+/// A Markov chain of configurable order.
+///
+/// A chain of order `n` learns transitions from each of the last one
+/// through `n` songs. When choosing, it uses the longest context it
+/// has data for, and backs off to shorter ones otherwise.
+///
+/// If a half-life is set, evidence decays exponentially with the time
+/// since its transition was last observed. This is applied lazily,
+/// whenever an edge is used or modified.
 #[derive(PartialEq)]
 pub struct Chain<T>
 where
-    T: Eq + Hash,
+    T: Eq + Hash + Ord,
 {
-    assocs: HashMap<T, HashMap<T, f32>>,
+    order: usize,
+    half_life: Option<f64>,
+    assocs: HashMap<Vec<T>, Successors<T>>,
 }
 
 impl<T> Chain<T>
 where
-    T: Eq + Hash,
+    T: Eq + Hash + Ord,
 {
-    pub fn new() -> Self {
+    pub fn new(order: usize) -> Self {
+        assert!(order > 0, "Chain order must be positive");
+
         Chain {
+            order,
+            half_life: None,
             assocs: HashMap::new(),
         }
     }
 
-    pub fn modify_weight(&mut self, prev: T, next: T, diff: f32) {
+    /// Sets the half-life of evidence, in seconds.
+    pub fn with_half_life(mut self, half_life: Option<f64>) -> Self {
+        assert!(half_life.is_none_or(|h| h > 0.0), "Half-life must be positive");
+
+        self.half_life = half_life;
+        self
+    }
+
+    #[inline]
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// An edge as it stands at the given time, after decay.
+    pub fn edge_at(&self, edge: &Edge, now: i64) -> Edge {
+        let mut edge = *edge;
+        edge.decay(now, self.half_life);
+        edge
+    }
+
+    /// How long alias tables may be used before decay makes them stale.
+    fn table_lifetime(&self) -> Option<i64> {
+        self.half_life.map(|half_life| (half_life * TABLE_LIFETIME) as i64)
+    }
+
+    /// Adds feedback for a transition. Positive values
+    /// reinforce it, while negative values discourage it.
+    pub fn modify_weight(&mut self, context: Vec<T>, next: T, diff: f32) {
         assert!(diff.is_finite());
+        debug_assert!(!context.is_empty() && context.len() <= self.order);
+
+        let now = utils::now();
+        let half_life = self.half_life;
+        let edge = self.assocs
+            .entry(context)
+            .or_insert_with(Successors::new)
+            .entry(next, || Edge::new(now));
+
+        edge.decay(now, half_life);
+        edge.observe(diff);
+    }
+
+    pub fn set_weight(&mut self, context: Vec<T>, next: T, edge: Edge) {
+        assert!(edge.successes.is_finite() && edge.failures.is_finite());
+        debug_assert!(!context.is_empty() && context.len() <= self.order);
+
+        *self.assocs
+            .entry(context)
+            .or_insert_with(Successors::new)
+            .entry(next, || edge) = edge;
+    }
+
+    /// Removes the outgoing transitions of every context ending in `item`.
+    #[allow(dead_code)]
+    pub fn clear(&mut self, item: &T) {
+        self.assocs.retain(|context, _| context.last() != Some(item));
+    }
+
+    /// Forgets `items` entirely, removing every transition into them
+    /// and every context they appear in. Returns how many transitions
+    /// were removed.
+    pub fn remove(&mut self, items: &HashSet<T>) -> usize {
+        let mut removed = 0;
+
+        self.assocs.retain(|context, probs| {
+            if context.iter().any(|item| items.contains(item)) {
+                removed += probs.len();
+                return false;
+            }
+
+            let before = probs.len();
+            probs.retain(|next| !items.contains(next));
+            removed += before - probs.len();
+
+            !probs.is_empty()
+        });
 
-        let probs = self.assocs.entry(prev).or_insert_with(HashMap::new);
-        let weight = probs.entry(next).or_insert(0.0);
-        *weight = sigmoid(*weight + diff);
+        removed
     }
 
-    pub fn clear<U>(&mut self, item: &U)
-    where U: Borrow<T>,
-          U: ?Sized,
+    /// Replaces items according to `renames`, wherever they appear.
+    /// Transitions which end up the same have their evidence merged.
+    /// Returns whether anything was renamed.
+    pub fn rename(&mut self, renames: &HashMap<T, T>) -> bool
+    where T: Clone,
     {
-        if let Some(ref mut probs) = self.assocs.get_mut(item.borrow()) {
-            probs.clear();
+        let renamed = self.assocs.iter().any(|(context, probs)| {
+            context.iter().chain(probs.items()).any(|item| renames.contains_key(item))
+        });
+
+        if !renamed {
+            return false;
         }
+
+        let rename = |item: T| match renames.get(&item) {
+            Some(new) => new.clone(),
+            None => item,
+        };
+
+        let half_life = self.half_life;
+        let assocs = mem::take(&mut self.assocs);
+        for (context, probs) in assocs {
+            let context = context.into_iter().map(&rename).collect();
+            let new_probs = self.assocs.entry(context).or_insert_with(Successors::new);
+
+            for (next, edge) in probs.items.into_iter().zip(probs.edges) {
+                let mut inserted = false;
+                let target = new_probs.entry(rename(next), || {
+                    inserted = true;
+                    edge
+                });
+
+                if !inserted {
+                    target.merge(edge, half_life);
+                }
+            }
+        }
+
+        true
     }
 
-    pub fn start(&self, rng: &mut Rng) -> Option<&T> {
-        random_key(&self.assocs, rng)
+    /// Every item which appears in the chain, whether in
+    /// a context or as the target of a transition.
+    pub fn items(&self) -> HashSet<&T> {
+        let mut items = HashSet::new();
+
+        for (context, probs) in &self.assocs {
+            items.extend(context);
+            items.extend(probs.items());
+        }
+
+        items
     }
 
-    pub fn next<U>(&self, current: &U, rng: &mut Rng) -> Option<&T>
-    where U: Borrow<T>,
-          U: ?Sized,
-    {
-        match self.assocs.get(current.borrow()) {
-            Some(probs) => roulette_wheel(probs, rng),
-            None => None,
+    pub fn start(&self, rng: &mut dyn Rng) -> Option<&T> {
+        let songs = self.assocs
+            .keys()
+            .filter(|context| context.len() == 1)
+            .map(|context| &context[0]);
+
+        random_key(songs, rng)
+    }
+
+    pub fn next(
+        &self,
+        history: &[T],
+        strategy: &dyn SelectionStrategy,
+        rng: &mut dyn Rng,
+    ) -> Option<Choice<&T>> {
+        let now = utils::now();
+        for context in contexts(history, self.order) {
+            let probs = match self.assocs.get(context) {
+                Some(probs) => probs,
+                None => continue,
+            };
+
+            if strategy.is_proportional() {
+                let weight = |edge: &Edge| self.edge_at(edge, now).mean();
+                if let Some(idx) = probs.sample(now, self.table_lifetime(), weight, rng) {
+                    return Some(Choice::Pick(&probs.items()[idx]));
+                }
+
+                continue;
+            }
+
+            let posteriors = probs
+                .edges()
+                .iter()
+                .map(|edge| self.edge_at(edge, now).alpha_beta())
+                .collect::<Vec<_>>();
+
+            if let Some(choice) = strategy.choose_posterior(&posteriors, rng) {
+                return Some(choice.map(|idx| &probs.items()[idx]));
+            }
         }
+
+        None
     }
 
-    pub fn possible_next<U>(&self, current: &U) -> Option<&HashMap<T, f32>>
-    where U: Borrow<T>,
-          U: ?Sized,
-    {
-        self.assocs.get(current.borrow())
+    /// How many successors the context used for `history` has,
+    /// indicating how much data the next choice would be based on.
+    pub fn support(&self, history: &[T]) -> usize {
+        contexts(history, self.order)
+            .filter_map(|context| self.assocs.get(context))
+            .map(Successors::len)
+            .find(|&len| len > 0)
+            .unwrap_or(0)
+    }
+
+    /// How many contexts have any transitions out of them.
+    pub fn context_count(&self) -> usize {
+        self.assocs.len()
+    }
+
+    pub fn transition_count(&self) -> usize {
+        self.assocs.values().map(Successors::len).sum()
+    }
+
+    pub fn possible_next(&self, context: &[T]) -> Option<&Successors<T>> {
+        self.assocs.get(context)
     }
 }
 
 impl<T> Clone for Chain<T>
 where
-    T: Eq + Hash,
+    T: Eq + Hash + Ord,
     T: Clone,
 {
     fn clone(&self) -> Self {
         Chain {
+            order: self.order,
+            half_life: self.half_life,
             assocs: self.assocs.clone(),
         }
     }
 
     fn clone_from(&mut self, source: &Self) {
+        self.order = source.order;
+        self.half_life = source.half_life;
         self.assocs.clone_from(&source.assocs);
     }
 }
 
 impl<T> Debug for Chain<T>
 where
-    T: Eq + Hash,
+    T: Eq + Hash + Ord,
     T: Debug,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Chain")
+         .field("order", &self.order)
+         .field("half_life", &self.half_life)
          .field("assocs", &self.assocs)
          .finish()
     }
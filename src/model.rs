@@ -0,0 +1,409 @@
+/*
+ * model.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use {markov, utils, Error, Result};
+use config::Config;
+use database::{self, Database, SqliteDatabase, StorageStats};
+use identity::{identify, SongIndex};
+use interner::Interner;
+use markov::Edge;
+use mpd::Song;
+use player::{Player, Transition};
+use reconcile::{self, Reconciliation};
+use std::collections::{HashMap, HashSet};
+use tags::TagChains;
+
+/// Name of the stored setting for whether the queue is managed.
+const RECOMMEND_SETTING: &str = "recommend";
+
+/// Everything learned from listening, along with the database it
+/// is persisted in. This is shared between the main loop and the
+/// control socket, so that commands can inspect and modify it.
+#[derive(Debug)]
+pub struct Model {
+    interner: Interner,
+    chain: markov::Chain<u32>,
+    tags: TagChains,
+    songs: SongIndex,
+    database: SqliteDatabase,
+}
+
+/// The changes to make to bring the model up to date with the library.
+#[derive(Debug)]
+struct Refresh {
+    /// File paths which transitions were learned under, and the
+    /// identities of the songs now at them.
+    legacy: HashMap<String, String>,
+    index: SongIndex,
+    reconciliation: Reconciliation,
+}
+
+impl Refresh {
+    /// The identity a learned song will have once this is applied.
+    fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+        let id = self.legacy.get(name).map_or(name, String::as_str);
+        self.reconciliation.renames.get(id).map_or(id, String::as_str)
+    }
+}
+
+impl Model {
+    pub fn load(config: &Config) -> Result<Self> {
+        let half_life = config.learning.half_life();
+        let mut database = SqliteDatabase::open(&config.daemon.storage_file, half_life)?;
+        let mut interner = Interner::new();
+        let chain = database::load_chain(&mut database, &mut interner, config.learning.order, half_life)?;
+        let tags = database::load_tag_chains(&mut database, &config.backoff.tags, half_life)?;
+        let songs = database::load_songs(&mut database)?;
+
+        Ok(Model {
+            interner,
+            chain,
+            tags,
+            songs,
+            database,
+        })
+    }
+
+    #[inline]
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
+
+    #[inline]
+    pub fn chain(&self) -> &markov::Chain<u32> {
+        &self.chain
+    }
+
+    #[inline]
+    pub fn tags(&self) -> &TagChains {
+        &self.tags
+    }
+
+    #[inline]
+    pub fn songs(&self) -> &SongIndex {
+        &self.songs
+    }
+
+    /// Finds a song's identity, given either the identity
+    /// itself or the song's current URI.
+    pub fn find_song<'a>(&'a self, song: &'a str) -> Option<&'a str> {
+        if self.interner.get(song).is_some() || self.songs.get(song).is_some() {
+            return Some(song);
+        }
+
+        self.songs.find_uri(song)
+    }
+
+    /// How to show a song: its URI if it is in the library,
+    /// or otherwise its identity.
+    pub fn display<'a>(&'a self, id: &'a str) -> &'a str {
+        self.songs.uri(id).unwrap_or(id)
+    }
+
+    /// The learned successors of the songs in `history`, along with how
+    /// likely each is to be chosen proportionally, most likely first.
+    /// Like when choosing, the longest context with any data is used.
+    pub fn successors(&self, history: &[String]) -> Vec<(&str, f32)> {
+        let mut keys = history
+            .iter()
+            .rev()
+            .map_while(|id| self.interner.get(id))
+            .collect::<Vec<_>>();
+
+        keys.reverse();
+
+        let probs = markov::contexts(&keys, self.chain.order())
+            .filter_map(|context| self.chain.possible_next(context))
+            .find(|probs| !probs.is_empty());
+
+        let probs = match probs {
+            Some(probs) => probs,
+            None => return Vec::new(),
+        };
+
+        let now = utils::now();
+        let weights = probs
+            .edges()
+            .iter()
+            .map(|edge| self.chain.edge_at(edge, now).mean())
+            .collect::<Vec<_>>();
+
+        let total = weights.iter().sum::<f32>();
+        let mut successors = probs
+            .items()
+            .iter()
+            .zip(weights)
+            .map(|(&key, weight)| (self.interner.resolve(key), weight / total))
+            .collect::<Vec<_>>();
+
+        successors.sort_by(|a, b| b.1.total_cmp(&a.1));
+        successors
+    }
+
+    /// The `count` strongest transitions between single songs, by
+    /// their posterior mean like proportional choice, strongest first.
+    pub fn top_transitions(&mut self, count: usize) -> Result<Vec<(String, String, Edge)>> {
+        let now = utils::now();
+        let chain = &self.chain;
+        let mut top: Vec<(String, String, Edge)> = Vec::new();
+
+        self.database.for_each_association(|assoc| {
+            if assoc.song.contains('\n') {
+                return;
+            }
+
+            let edge = chain.edge_at(&assoc.edge(), now);
+            let idx = top.partition_point(|entry| entry.2.mean() >= edge.mean());
+
+            if idx < count {
+                top.insert(idx, (assoc.song, assoc.next, edge));
+                top.truncate(count);
+            }
+        })?;
+
+        Ok(top)
+    }
+
+    pub fn storage_stats(&mut self) -> Result<StorageStats> {
+        self.database.stats()
+    }
+
+    pub fn learn(&mut self, transition: Transition, config: &Config) -> Result<()> {
+        let Transition { history, next, listen } = transition;
+        let diff = listen.weight_delta(&config.learning);
+        let ids = history.iter().map(identify).collect::<Vec<_>>();
+
+        debug!("Learning from {:?}", listen);
+        self.observe(&ids, &identify(&next), diff)?;
+
+        let prev = match history.last() {
+            Some(prev) => prev,
+            None => return Ok(()),
+        };
+
+        for &tag in &config.backoff.tags {
+            if let (Some(from), Some(to)) = (tag.value(prev), tag.value(&next)) {
+                self.database.modify_tag_weight(tag.as_str(), from, to, diff)?;
+
+                if let Some(chain) = self.tags.get_mut(tag) {
+                    chain.modify_weight(vec![from.into()], to.into(), diff);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds evidence for `next` following the songs in `history`,
+    /// identified by their identities, in every context it applies to.
+    pub fn observe(&mut self, history: &[String], next: &str, diff: f32) -> Result<()> {
+        let keys = history.iter().map(|id| self.interner.intern(id)).collect::<Vec<_>>();
+        let next_key = self.interner.intern(next);
+
+        let order = self.chain.order();
+        for (context, key) in markov::contexts(history, order).zip(markov::contexts(&keys, order)) {
+            debug!("Modifying weight {:?} -> {} by {}", context, next, diff);
+
+            let encoded = database::encode_context(context);
+            self.database.modify_weight(&encoded, next, diff)?;
+            self.chain.modify_weight(key.to_vec(), next_key, diff);
+        }
+
+        Ok(())
+    }
+
+    /// Forgets what has been learned about what follows a song, and
+    /// about it following others, including in every context it is
+    /// part of. Returns how many transitions were removed.
+    pub fn forget(&mut self, id: &str) -> Result<usize> {
+        let key = match self.interner.get(id) {
+            Some(key) => key,
+            None => return Ok(0),
+        };
+
+        let ids = [id.to_owned()].iter().cloned().collect::<HashSet<_>>();
+        self.database.remove_songs(&ids)?;
+
+        let keys = [key].iter().cloned().collect::<HashSet<_>>();
+        let removed = self.chain.remove(&keys);
+
+        info!("Forgot {} transitions involving {}", removed, id);
+        Ok(removed)
+    }
+
+    /// Rebuilds the index of songs from the library, reconciling songs
+    /// which have moved so that what was learned about them is kept.
+    ///
+    /// Transitions learned before songs had identities are keyed by URI,
+    /// so those are moved over to the songs' identities here too.
+    pub fn refresh_songs(&mut self, player: &mut Player) -> Result<Reconciliation> {
+        let songs = player.songs()?;
+        self.refresh_library(&songs)
+    }
+
+    /// Like `refresh_songs`, given the songs in the library, so that
+    /// they can be fetched without holding up others using the model.
+    pub fn refresh_library(&mut self, songs: &[Song]) -> Result<Reconciliation> {
+        let refresh = self.plan_refresh(songs);
+        self.apply_refresh(refresh)
+    }
+
+    /// Works out what refreshing from the library `songs` would change,
+    /// without changing anything.
+    fn plan_refresh(&self, songs: &[Song]) -> Refresh {
+        let legacy = songs
+            .iter()
+            .map(|song| (song.file.clone(), identify(song)))
+            .collect::<HashMap<_, _>>();
+
+        let mut index = SongIndex::from_songs(songs);
+        let reconciliation = {
+            let learned = self.chain
+                .items()
+                .into_iter()
+                .map(|&key| self.interner.resolve(key))
+                .map(|name| legacy.get(name).map_or(name, String::as_str))
+                .collect::<HashSet<_>>();
+
+            reconcile::reconcile(&self.songs, &mut index, |id| learned.contains(id))
+        };
+
+        Refresh {
+            legacy,
+            index,
+            reconciliation,
+        }
+    }
+
+    fn apply_refresh(&mut self, refresh: Refresh) -> Result<Reconciliation> {
+        let Refresh {
+            legacy,
+            index,
+            reconciliation: result,
+        } = refresh;
+
+        if self.rename(&legacy) {
+            info!("Moving learned transitions from file paths to song identities");
+            self.database.rename_songs(&legacy)?;
+        }
+
+        if !result.renames.is_empty() {
+            info!("Reconciled {} moved songs", result.renames.len());
+            self.rename(&result.renames);
+            self.database.rename_songs(&result.renames)?;
+        }
+
+        for id in &result.unmatched {
+            warn!("Song {} is missing from the library", id);
+        }
+
+        self.songs = index;
+        debug!("Indexed {} songs from the library", self.songs.len());
+
+        self.database.replace_songs(&self.songs)?;
+        Ok(result)
+    }
+
+    /// Finds learned songs which are no longer in the library `songs`, even
+    /// after reconciling moved files, and unless `dry_run` is set, forgets
+    /// them. Returns the identities of those songs. A dry run changes nothing.
+    pub fn collect_garbage(&mut self, songs: &[Song], dry_run: bool) -> Result<Vec<String>> {
+        // An empty library is more likely unmounted than deleted,
+        // and would make every learned song look missing
+        if songs.is_empty() {
+            return Err(Error::StaticMsg("Library is empty, not collecting garbage"));
+        }
+
+        let refresh = self.plan_refresh(songs);
+
+        if dry_run {
+            let mut missing = self.chain
+                .items()
+                .into_iter()
+                .map(|&key| refresh.resolve(self.interner.resolve(key)))
+                .filter(|id| refresh.index.uri(id).is_none())
+                .map(str::to_owned)
+                .collect::<Vec<_>>();
+
+            missing.sort();
+            missing.dedup();
+            return Ok(missing);
+        }
+
+        self.apply_refresh(refresh)?;
+
+        let missing_keys = self.chain
+            .items()
+            .into_iter()
+            .cloned()
+            .filter(|&key| self.songs.uri(self.interner.resolve(key)).is_none())
+            .collect::<HashSet<_>>();
+
+        let mut missing = missing_keys
+            .iter()
+            .map(|&key| self.interner.resolve(key).to_owned())
+            .collect::<Vec<_>>();
+
+        missing.sort();
+
+        if missing.is_empty() {
+            return Ok(missing);
+        }
+
+        let removed = missing.iter().cloned().collect::<HashSet<_>>();
+        let count = self.chain.remove(&missing_keys);
+        self.database.remove_songs(&removed)?;
+        info!("Removed {} transitions involving {} deleted songs", count, removed.len());
+
+        for id in &removed {
+            self.songs.remove(id);
+        }
+
+        self.database.replace_songs(&self.songs)?;
+        Ok(missing)
+    }
+
+    /// Whether the queue was last set to be managed, if it was
+    /// ever changed over the control socket.
+    pub fn saved_recommend(&mut self) -> Result<Option<bool>> {
+        let value = self.database.setting(RECOMMEND_SETTING)?;
+        Ok(value.map(|value| value == "on"))
+    }
+
+    /// Stores whether the queue is managed, overriding the configuration.
+    pub fn save_recommend(&mut self, value: bool) -> Result<()> {
+        let value = if value { "on" } else { "off" };
+        self.database.set_setting(RECOMMEND_SETTING, value)
+    }
+
+    /// Renames songs in the chain, by their identities.
+    /// Returns whether anything was renamed.
+    fn rename(&mut self, renames: &HashMap<String, String>) -> bool {
+        let keys = renames
+            .iter()
+            .filter_map(|(from, to)| {
+                let from = self.interner.get(from)?;
+                Some((from, self.interner.intern(to)))
+            })
+            .collect::<HashMap<_, _>>();
+
+        !keys.is_empty() && self.chain.rename(&keys)
+    }
+}
@@ -1,5 +1,5 @@
 /*
- * player/mpv_player.rs
+ * player.rs
  *
  * markov-music - A music player that uses Markov chains to choose songs
  * Copyright (c) 2017-2018 Ammon Smith
@@ -19,12 +19,263 @@
  */
 
 use Result;
-use config::MpdConfig;
-use mpd::{self, Idle};
+use config::{LearningConfig, MpdConfig};
+use mpd::{self, Channel, Idle, Query, Song, State, Subsystem, Term};
+use mpd::song::Id;
+use std::collections::VecDeque;
+use std::ops;
+use std::time::{Duration, Instant};
+use tags::Tag;
+
+/// How far the observed position may drift from our estimate before
+/// we consider it a seek rather than timing jitter.
+const SEEK_TOLERANCE: u64 = 3;
+
+/// How close to the end a song must get to count as finished.
+const FINISH_TOLERANCE: u64 = 5;
+
+/// How many songs to request at a time when listing the whole library.
+const LIBRARY_WINDOW: u32 = 1000;
+
+/// The mpd channel used to wake the main loop when
+/// settings are changed over the control socket.
+const CHANNEL: &str = "markov_music";
+
+const SUBSYSTEMS: [Subsystem; 5] = [
+    Subsystem::Player,
+    Subsystem::Queue,
+    Subsystem::Database,
+    Subsystem::Options,
+    Subsystem::Message,
+];
+
+fn channel() -> Channel {
+    Channel::new(CHANNEL).expect("Invalid channel name")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerEvent {
+    SongStarted(Song),
+    SongFinished(Song),
+
+    /// A song ended early, after the given time, with its
+    /// duration as reported by mpd.
+    SongSkipped(Song, Duration, Option<Duration>),
+    Seek(Song, Duration),
+    Stop,
+    QueueChanged,
+    DatabaseChanged,
+    OptionsChanged,
+    SettingsChanged,
+}
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Listen {
+    Completed,
+    SkippedEarly,
+    SkippedLate,
+    Replayed,
+}
+
+impl Listen {
+    pub fn weight_delta(self, config: &LearningConfig) -> f32 {
+        match self {
+            Listen::Completed => config.completed,
+            Listen::SkippedEarly => config.skipped_early,
+            Listen::SkippedLate => config.skipped_late,
+            Listen::Replayed => config.replayed,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Listen::Completed => "completed",
+            Listen::SkippedEarly => "skipped-early",
+            Listen::SkippedLate => "skipped-late",
+            Listen::Replayed => "replayed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+    /// The songs played before `next`, oldest first.
+    pub history: Vec<Song>,
+    pub next: Song,
+    pub listen: Listen,
+}
+
+/// Follows the stream of player events and classifies how each
+/// song was listened to, producing transitions to learn from.
+///
+/// A song's classification is only known once the following song
+/// starts, since starting the same song again counts as a replay.
+/// A replayed song stays the current one, and the transition into
+/// it is only learned once, as a replay.
+#[derive(Debug, Clone)]
+pub struct ListenTracker {
+    early_skip: f32,
+    order: usize,
+    history: VecDeque<Song>,
+    current: Option<Song>,
+    ended: Option<(Song, Listen)>,
+    replayed: bool,
+}
+
+impl ListenTracker {
+    pub fn new(config: &LearningConfig) -> Self {
+        ListenTracker {
+            early_skip: config.early_skip,
+            order: config.order,
+            history: VecDeque::with_capacity(config.order + 1),
+            current: None,
+            ended: None,
+            replayed: false,
+        }
+    }
+
+    /// The song playing, or last played until something else starts.
+    pub fn current(&self) -> Option<&Song> {
+        self.current.as_ref()
+    }
+
+    /// The songs heard before the current one, oldest first.
+    /// This is the context the transition into it is learned in.
+    pub fn history(&self) -> impl Iterator<Item = &Song> {
+        self.history.iter()
+    }
+
+    pub fn process(&mut self, event: &PlayerEvent) -> Option<Transition> {
+        match *event {
+            PlayerEvent::SongFinished(ref song) => {
+                self.ended = Some((song.clone(), Listen::Completed));
+                None
+            },
+            PlayerEvent::SongSkipped(ref song, elapsed, duration) => {
+                let listen = self.classify_skip(song, elapsed, duration);
+                self.ended = Some((song.clone(), listen));
+                None
+            },
+            PlayerEvent::SongStarted(ref song) => {
+                self.current = Some(song.clone());
+
+                match self.ended.take() {
+                    Some((ended, _)) if ended.file == song.file => {
+                        if self.replayed {
+                            return None;
+                        }
+
+                        self.replayed = true;
+                        self.transition(ended, Listen::Replayed)
+                    },
+                    Some((ended, listen)) => {
+                        let transition = self.finish(ended.clone(), listen);
+                        self.history.push_back(ended);
+                        if self.history.len() > self.order {
+                            self.history.pop_front();
+                        }
+
+                        transition
+                    },
+                    None => {
+                        self.replayed = false;
+                        None
+                    },
+                }
+            },
+            PlayerEvent::Stop => {
+                let transition = match self.ended.take() {
+                    Some((ended, listen)) => self.finish(ended, listen),
+                    None => None,
+                };
+
+                self.replayed = false;
+                self.current = None;
+                self.history.clear();
+                transition
+            },
+            _ => None,
+        }
+    }
+
+    fn classify_skip(&self, song: &Song, elapsed: Duration, duration: Option<Duration>) -> Listen {
+        let duration = match duration.or(song.duration) {
+            Some(duration) if duration.as_secs() > 0 => duration,
+            _ => return Listen::SkippedEarly,
+        };
+
+        let fraction = elapsed.as_secs_f32() / duration.as_secs_f32();
+        if fraction < self.early_skip {
+            Listen::SkippedEarly
+        } else {
+            Listen::SkippedLate
+        }
+    }
+
+    /// The transition into a song which has ended, unless it was
+    /// already learned when the song was replayed.
+    fn finish(&mut self, next: Song, listen: Listen) -> Option<Transition> {
+        if self.replayed {
+            self.replayed = false;
+            return None;
+        }
+
+        self.transition(next, listen)
+    }
+
+    fn transition(&self, next: Song, listen: Listen) -> Option<Transition> {
+        if self.history.is_empty() {
+            return None;
+        }
+
+        Some(Transition {
+            history: self.history.iter().cloned().collect(),
+            next,
+            listen,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Playing {
+    song: Song,
+    id: Id,
+    state: State,
+    elapsed: Duration,
+    duration: Option<Duration>,
+    updated: Instant,
+}
+
+impl Playing {
+    fn elapsed(&self) -> Duration {
+        match self.state {
+            State::Play => self.elapsed + self.updated.elapsed(),
+            _ => self.elapsed,
+        }
+    }
+
+    fn finished(&self) -> bool {
+        match self.duration {
+            Some(duration) => self.elapsed() + Duration::from_secs(FINISH_TOLERANCE) >= duration,
+            None => false,
+        }
+    }
+
+    fn end_event(self) -> PlayerEvent {
+        if self.finished() {
+            PlayerEvent::SongFinished(self.song)
+        } else {
+            let elapsed = self.elapsed();
+            PlayerEvent::SongSkipped(self.song, elapsed, self.duration)
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Player {
     conn: mpd::Client,
+    playing: Option<Playing>,
 }
 
 impl Player {
@@ -35,12 +286,305 @@ impl Player {
             conn.login(password)?;
         }
 
-        Ok(Player { conn })
+        let mut player = Player {
+            conn,
+            playing: None,
+        };
+
+        player.playing = player.fetch_playing()?;
+        Ok(player)
+    }
+
+    /// The song mpd was playing when it was last checked.
+    pub fn playing(&self) -> Option<&Song> {
+        self.playing.as_ref().map(|playing| &playing.song)
+    }
+
+    /// Listens for notifications sent with `notify()`,
+    /// which are reported as `SettingsChanged`.
+    pub fn subscribe(&mut self) -> Result<()> {
+        self.conn.subscribe(channel())?;
+        Ok(())
+    }
+
+    /// Tells subscribed players that settings have changed.
+    pub fn notify(&mut self) -> Result<()> {
+        self.conn.sendmessage(channel(), "settings")?;
+        Ok(())
+    }
+
+    /// Returns the songs in the queue, and the position of the current song within it.
+    pub fn queue(&mut self) -> Result<(Vec<Song>, Option<u32>)> {
+        let position = self.conn.status()?.song.map(|place| place.pos);
+        let queue = self.conn.queue()?;
+
+        Ok((queue, position))
+    }
+
+    /// Returns the paths of all songs in the mpd library.
+    pub fn library(&mut self) -> Result<Vec<String>> {
+        let songs = self.conn.listall()?;
+        Ok(songs.into_iter().map(|song| song.file).collect())
+    }
+
+    /// Returns all songs in the mpd library, along with their metadata.
+    pub fn songs(&mut self) -> Result<Vec<Song>> {
+        // Searching for the empty string matches everything, and unlike
+        // listall it includes tags. Fetch in windows so large libraries
+        // don't exceed mpd's output buffer.
+        let mut query = Query::new();
+        query.and(Term::Any, "");
+
+        let mut songs = Vec::new();
+        loop {
+            let start = songs.len() as u32;
+            let batch = self.conn.search(&query, (start, start + LIBRARY_WINDOW))?;
+            let done = (batch.len() as u32) < LIBRARY_WINDOW;
+
+            songs.extend(batch);
+            if done {
+                return Ok(songs);
+            }
+        }
+    }
+
+    /// Finds all songs in the library whose tag has exactly the given value.
+    pub fn find(&mut self, tag: Tag, value: &str) -> Result<Vec<Song>> {
+        let mut query = Query::new();
+        query.and(Term::Tag(tag.mpd_name().into()), value);
+
+        let songs = self.conn.find(&query, None)?;
+        Ok(songs)
+    }
+
+    /// Adds a song to the end of the queue, returning its metadata.
+    pub fn enqueue(&mut self, file: &str) -> Result<Song> {
+        let song = Song {
+            file: file.into(),
+            ..Song::default()
+        };
+
+        let id = self.conn.push(&song)?;
+        let queued = self.conn.playlistid(id)?;
+        Ok(queued.unwrap_or(song))
+    }
+
+    pub fn dequeue(&mut self, range: ops::Range<u32>) -> Result<()> {
+        self.conn.delete(range)?;
+        Ok(())
+    }
+
+    pub fn update(&mut self) -> Result<Vec<PlayerEvent>> {
+        let mut events = Vec::new();
+
+        for subsystem in self.conn.wait(&SUBSYSTEMS)? {
+            match subsystem {
+                Subsystem::Player => self.check_player(&mut events)?,
+                Subsystem::Queue => events.push(PlayerEvent::QueueChanged),
+                Subsystem::Database => events.push(PlayerEvent::DatabaseChanged),
+                Subsystem::Options => events.push(PlayerEvent::OptionsChanged),
+                // Only the latest settings matter, so any number
+                // of messages amount to a single event
+                Subsystem::Message if !self.conn.readmessages()?.is_empty() => {
+                    events.push(PlayerEvent::SettingsChanged)
+                },
+                _ => (),
+            }
+        }
+
+        Ok(events)
+    }
+
+    fn fetch_playing(&mut self) -> Result<Option<Playing>> {
+        let status = self.conn.status()?;
+        let id = match status.song {
+            Some(place) if status.state != State::Stop => place.id,
+            _ => return Ok(None),
+        };
+
+        let song = match self.conn.currentsong()? {
+            Some(song) => song,
+            None => return Ok(None),
+        };
+
+        let duration = status.duration.or(song.duration);
+        Ok(Some(Playing {
+            song,
+            id,
+            state: status.state,
+            elapsed: status.elapsed.unwrap_or_else(|| Duration::from_secs(0)),
+            duration,
+            updated: Instant::now(),
+        }))
+    }
+
+    fn check_player(&mut self, events: &mut Vec<PlayerEvent>) -> Result<()> {
+        let current = self.fetch_playing()?;
+
+        match (self.playing.take(), current.as_ref()) {
+            (Some(prev), Some(cur)) => {
+                if prev.id != cur.id || prev.song.file != cur.song.file {
+                    events.push(prev.end_event());
+                    events.push(PlayerEvent::SongStarted(cur.song.clone()));
+                } else if cur.elapsed < Duration::from_secs(SEEK_TOLERANCE) && prev.finished() {
+                    // The same song started over, e.g. with "single" and "repeat"
+                    events.push(PlayerEvent::SongFinished(prev.song));
+                    events.push(PlayerEvent::SongStarted(cur.song.clone()));
+                } else {
+                    let drift = prev.elapsed().abs_diff(cur.elapsed);
+                    if drift > Duration::from_secs(SEEK_TOLERANCE) {
+                        events.push(PlayerEvent::Seek(cur.song.clone(), cur.elapsed));
+                    }
+                }
+            },
+            (Some(prev), None) => {
+                events.push(prev.end_event());
+                events.push(PlayerEvent::Stop);
+            },
+            (None, Some(cur)) => events.push(PlayerEvent::SongStarted(cur.song.clone())),
+            (None, None) => (),
+        }
+
+        self.playing = current;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(file: &str) -> Song {
+        Song {
+            file: file.to_owned(),
+            ..Song::default()
+        }
+    }
+
+    fn tracker() -> ListenTracker {
+        let config = LearningConfig {
+            order: 1,
+            early_skip: 0.5,
+            ..LearningConfig::default()
+        };
+
+        ListenTracker::new(&config)
+    }
+
+    /// Feeds the tracker the events, returning each transition
+    /// as the songs' files and how the last was listened to.
+    fn run(tracker: &mut ListenTracker, events: &[PlayerEvent]) -> Vec<(Vec<String>, String, Listen)> {
+        events
+            .iter()
+            .filter_map(|event| tracker.process(event))
+            .map(|transition| {
+                let history = transition.history.into_iter().map(|song| song.file).collect();
+                (history, transition.next.file, transition.listen)
+            })
+            .collect()
+    }
+
+    fn expected(from: &str, to: &str, listen: Listen) -> (Vec<String>, String, Listen) {
+        (vec![from.to_owned()], to.to_owned(), listen)
+    }
+
+    #[test]
+    fn finish() {
+        let events = [
+            PlayerEvent::SongStarted(song("a")),
+            PlayerEvent::SongFinished(song("a")),
+            PlayerEvent::SongStarted(song("b")),
+            PlayerEvent::SongFinished(song("b")),
+            PlayerEvent::SongStarted(song("c")),
+        ];
+
+        assert_eq!(run(&mut tracker(), &events), vec![expected("a", "b", Listen::Completed)]);
+    }
+
+    #[test]
+    fn skip() {
+        let secs = Duration::from_secs;
+        let events = [
+            PlayerEvent::SongStarted(song("a")),
+            PlayerEvent::SongFinished(song("a")),
+            PlayerEvent::SongStarted(song("b")),
+            PlayerEvent::SongSkipped(song("b"), secs(10), Some(secs(200))),
+            PlayerEvent::SongStarted(song("c")),
+            PlayerEvent::SongSkipped(song("c"), secs(150), Some(secs(200))),
+            PlayerEvent::SongStarted(song("d")),
+            PlayerEvent::SongSkipped(song("d"), secs(150), None),
+            PlayerEvent::SongStarted(song("e")),
+        ];
+
+        assert_eq!(
+            run(&mut tracker(), &events),
+            vec![
+                expected("a", "b", Listen::SkippedEarly),
+                expected("b", "c", Listen::SkippedLate),
+                expected("c", "d", Listen::SkippedEarly),
+            ],
+        );
+    }
+
+    #[test]
+    fn replay() {
+        let events = [
+            PlayerEvent::SongStarted(song("a")),
+            PlayerEvent::SongFinished(song("a")),
+            PlayerEvent::SongStarted(song("b")),
+            PlayerEvent::SongFinished(song("b")),
+            PlayerEvent::SongStarted(song("b")),
+            PlayerEvent::SongFinished(song("b")),
+            PlayerEvent::SongStarted(song("b")),
+            PlayerEvent::SongSkipped(song("b"), Duration::from_secs(1), None),
+            PlayerEvent::SongStarted(song("c")),
+            PlayerEvent::SongFinished(song("c")),
+            PlayerEvent::SongStarted(song("d")),
+        ];
+
+        assert_eq!(
+            run(&mut tracker(), &events),
+            vec![
+                expected("a", "b", Listen::Replayed),
+                expected("b", "c", Listen::Completed),
+            ],
+        );
+    }
+
+    #[test]
+    fn current() {
+        let mut tracker = tracker();
+        let events = [
+            PlayerEvent::SongStarted(song("a")),
+            PlayerEvent::SongFinished(song("a")),
+            PlayerEvent::SongStarted(song("b")),
+            PlayerEvent::SongFinished(song("b")),
+            PlayerEvent::SongStarted(song("b")),
+        ];
+
+        run(&mut tracker, &events);
+        let history = tracker.history().map(|song| song.file.as_str()).collect::<Vec<_>>();
+        assert_eq!(history, vec!["a"]);
+        assert_eq!(tracker.current().map(|song| song.file.as_str()), Some("b"));
+
+        run(&mut tracker, &[PlayerEvent::Stop]);
+        assert_eq!(tracker.history().count(), 0);
+        assert_eq!(tracker.current(), None);
     }
 
-    pub fn update(&mut self) -> Result<()> {
-        let _ = self.conn.wait(&[])?;
+    #[test]
+    fn stop() {
+        let events = [
+            PlayerEvent::SongStarted(song("a")),
+            PlayerEvent::SongFinished(song("a")),
+            PlayerEvent::SongStarted(song("b")),
+            PlayerEvent::SongSkipped(song("b"), Duration::from_secs(1), None),
+            PlayerEvent::Stop,
+            PlayerEvent::SongStarted(song("c")),
+            PlayerEvent::SongFinished(song("c")),
+            PlayerEvent::SongStarted(song("d")),
+        ];
 
-        unimplemented!()
+        assert_eq!(run(&mut tracker(), &events), vec![expected("a", "b", Listen::SkippedEarly)]);
     }
 }
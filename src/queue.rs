@@ -0,0 +1,232 @@
+/*
+ * queue.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use {choose, Result};
+use choose::{Choice, SelectionStrategy};
+use config::Config;
+use identity::identify;
+use model::Model;
+use mpd::Song;
+use player::Player;
+use rand::Rng;
+use settings::Settings;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use tags::Tag;
+
+/// Keeps the mpd queue stocked with songs chosen by the chain,
+/// and trims songs which have already been played.
+#[derive(Debug)]
+pub struct QueueManager {
+    upcoming: u32,
+    history: u32,
+    min_successors: usize,
+    settings: Arc<Settings>,
+    library: Vec<String>,
+}
+
+impl QueueManager {
+    pub fn new(config: &Config, settings: Arc<Settings>) -> Self {
+        QueueManager {
+            upcoming: config.queue.upcoming,
+            history: config.queue.history,
+            min_successors: config.backoff.min_successors,
+            settings,
+            library: Vec::new(),
+        }
+    }
+
+    /// Forgets the cached list of library songs, so it is
+    /// fetched again the next time it is needed.
+    pub fn invalidate_library(&mut self) {
+        self.library.clear();
+    }
+
+    /// Tops up the queue with chosen songs, returning the ones added.
+    ///
+    /// The model is only locked while choosing each song,
+    /// never while waiting on mpd.
+    pub fn update(
+        &mut self,
+        player: &mut Player,
+        model: &Mutex<Model>,
+        rng: &mut dyn Rng,
+    ) -> Result<Vec<String>> {
+        let mut queued = Vec::new();
+        if !self.settings.recommend() {
+            return Ok(queued);
+        }
+
+        let strategy = choose::strategy(&self.settings.strategy());
+
+        let (queue, position) = player.queue()?;
+        let upcoming = match position {
+            Some(pos) => (queue.len() as u32).saturating_sub(pos + 1),
+            None => queue.len() as u32,
+        };
+
+        let order = model.lock().expect("Model lock poisoned").chain().order();
+        let skip = queue.len().saturating_sub(order);
+        let mut history = queue
+            .iter()
+            .skip(skip)
+            .map(identify)
+            .collect::<Vec<_>>();
+        let mut last = queue.last().cloned();
+
+        for _ in upcoming..self.upcoming {
+            let plan = {
+                let model = model.lock().expect("Model lock poisoned");
+                self.plan(&model, &*strategy, &history, last.as_ref(), rng)
+            };
+
+            let next = match self.pick(player, plan, &history, rng)? {
+                Some(file) => file,
+                None => break,
+            };
+
+            info!("Queueing {}", next);
+            let song = player.enqueue(&next)?;
+            queued.push(next);
+
+            history.push(identify(&song));
+            last = Some(song);
+            if history.len() > order {
+                history.remove(0);
+            }
+        }
+
+        if let Some(pos) = position {
+            if pos > self.history {
+                let trim = pos - self.history;
+                debug!("Removing {} played songs from the queue", trim);
+                player.dequeue(0..trim)?;
+            }
+        }
+
+        Ok(queued)
+    }
+
+    /// Works out what the model would queue after `history`.
+    fn plan(
+        &self,
+        model: &Model,
+        strategy: &dyn SelectionStrategy,
+        history: &[String],
+        last: Option<&Song>,
+        rng: &mut dyn Rng,
+    ) -> Plan {
+        let interner = model.interner();
+        let chain = model.chain();
+
+        // Songs the chain has never seen can't be part of any context,
+        // so only the songs since the last unknown one are relevant
+        let mut keys = history
+            .iter()
+            .rev()
+            .map_while(|id| interner.get(id))
+            .collect::<Vec<_>>();
+
+        keys.reverse();
+
+        let mut backoff = Vec::new();
+        if chain.support(&keys) < self.min_successors {
+            if let Some(last) = last {
+                for &(tag, ref chain) in model.tags().iter() {
+                    let value = match tag.value(last) {
+                        Some(value) => value.to_owned(),
+                        None => continue,
+                    };
+
+                    let next = match chain.next(slice::from_ref(&value), strategy, rng) {
+                        Some(Choice::Pick(next)) => next.clone(),
+                        _ => continue,
+                    };
+
+                    backoff.push((tag, value, next));
+                }
+            }
+        }
+
+        let key = match chain.next(&keys, strategy, rng) {
+            Some(Choice::Pick(key)) => Some(key),
+            Some(Choice::Explore) => None,
+            None => chain.start(rng),
+        };
+
+        let id = key.map(|&key| interner.resolve(key));
+        let uri = id.and_then(|id| model.songs().uri(id)).map(String::from);
+
+        Plan { backoff, uri }
+    }
+
+    fn pick(
+        &mut self,
+        player: &mut Player,
+        plan: Plan,
+        history: &[String],
+        rng: &mut dyn Rng,
+    ) -> Result<Option<String>> {
+        // Pick a song whose tags follow on from the last song's,
+        // trying each tag chain in turn
+        for (tag, value, next) in plan.backoff {
+            let candidates = player
+                .find(tag, &next)?
+                .into_iter()
+                .filter(|song| !history.contains(&identify(song)))
+                .map(|song| song.file)
+                .collect::<Vec<_>>();
+
+            if let Some(file) = choose::random_key(&candidates, rng) {
+                debug!("Backing off to {} transition {:?} -> {}", tag.as_str(), value, next);
+                return Ok(Some(file.clone()));
+            }
+        }
+
+        match plan.uri {
+            Some(uri) => Ok(Some(uri)),
+            None => self.random_song(player, rng),
+        }
+    }
+
+    fn random_song(&mut self, player: &mut Player, rng: &mut dyn Rng) -> Result<Option<String>> {
+        if self.library.is_empty() {
+            self.library = player.library()?;
+            debug!("Fetched {} songs from the library", self.library.len());
+        }
+
+        if self.library.is_empty() {
+            return Ok(None);
+        }
+
+        let index = (rng.next_f64() * self.library.len() as f64) as usize;
+        Ok(self.library.get(index).cloned())
+    }
+}
+
+/// What the model would queue next, decided while it was locked.
+#[derive(Debug)]
+struct Plan {
+    /// Tag transitions to back off to, in order of preference.
+    backoff: Vec<(Tag, String, String)>,
+
+    /// The song the chain chose, if it is in the library.
+    uri: Option<String>,
+}
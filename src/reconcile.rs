@@ -0,0 +1,264 @@
+/*
+ * reconcile.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use identity::{normalize, SongIndex, SongInfo};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// How many seconds a moved file's duration may differ by,
+/// since different tools round durations differently.
+const DURATION_TOLERANCE: u64 = 1;
+
+/// The outcome of matching songs which vanished from the
+/// library against those which newly appeared in it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Reconciliation {
+    /// Old song identities, and the identities they were matched to.
+    pub renames: HashMap<String, String>,
+
+    /// Songs with learned data which could not be matched.
+    /// These are kept, marked as missing, rather than dropped.
+    pub unmatched: Vec<String>,
+}
+
+/// Matches songs in `old` which are no longer in `new` to the songs
+/// which have been added, by their tags and duration. Only songs
+/// which `learned` is true for are considered, as there is nothing
+/// to preserve for the rest.
+///
+/// Unmatched songs are added to `new` as missing, so they can still
+/// be matched by a later reconciliation.
+pub fn reconcile<F>(old: &SongIndex, new: &mut SongIndex, learned: F) -> Reconciliation
+where
+    F: Fn(&str) -> bool,
+{
+    let mut vanished = old
+        .iter()
+        .filter(|&(id, _)| new.get(id).is_none() && learned(id))
+        .collect::<Vec<_>>();
+
+    // Sort so the results do not depend on hash map iteration order
+    vanished.sort_by(|a, b| a.0.cmp(b.0));
+
+    let appeared = new
+        .iter()
+        .filter(|&(id, _)| old.get(id).is_none())
+        .map(|(id, info)| (id.to_owned(), info.clone()))
+        .collect::<Vec<_>>();
+
+    let mut result = Reconciliation::default();
+    let mut claimed = HashSet::new();
+
+    for (id, info) in vanished {
+        let mut candidates = appeared
+            .iter()
+            .filter(|&(new_id, _)| !claimed.contains(new_id))
+            .filter(|&(_, new_info)| matches(info, new_info));
+
+        // Only trust a match if it is unambiguous
+        match (candidates.next(), candidates.next()) {
+            (Some((new_id, _)), None) => {
+                claimed.insert(new_id.clone());
+                result.renames.insert(id.to_owned(), new_id.clone());
+            }
+            _ => {
+                let mut info = info.clone();
+                info.missing = true;
+
+                new.insert(id.to_owned(), info);
+                result.unmatched.push(id.to_owned());
+            }
+        }
+    }
+
+    result
+}
+
+/// Whether a song which appeared in the library is likely the same
+/// file as one which vanished. Their durations must agree, none of
+/// their tags may conflict, and they must either share a tag or
+/// have the same file name.
+fn matches(old: &SongInfo, new: &SongInfo) -> bool {
+    match (old.duration, new.duration) {
+        (Some(a), Some(b)) if a.max(b) - a.min(b) <= DURATION_TOLERANCE => (),
+        (None, None) => (),
+        _ => return false,
+    }
+
+    let tags = [
+        (&old.artist, &new.artist),
+        (&old.title, &new.title),
+        (&old.album, &new.album),
+    ];
+
+    let mut shared = 0;
+    for &(a, b) in &tags {
+        if let (Some(a), Some(b)) = (a, b) {
+            if normalize(a) != normalize(b) {
+                return false;
+            }
+
+            shared += 1;
+        }
+    }
+
+    shared > 0 || file_name(&old.uri) == file_name(&new.uri)
+}
+
+fn file_name(uri: &str) -> Option<&str> {
+    Path::new(uri).file_name().and_then(|name| name.to_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(uri: &str, tags: Option<(&str, &str)>, duration: Option<u64>) -> SongInfo {
+        SongInfo {
+            uri: uri.to_owned(),
+            artist: tags.map(|(artist, _)| artist.to_owned()),
+            title: tags.map(|(_, title)| title.to_owned()),
+            album: None,
+            duration,
+            missing: false,
+        }
+    }
+
+    fn index(songs: &[(&str, SongInfo)]) -> SongIndex {
+        let mut index = SongIndex::new();
+        for (id, info) in songs {
+            index.insert((*id).to_owned(), info.clone());
+        }
+
+        index
+    }
+
+    struct Case {
+        name: &'static str,
+        old: Vec<(&'static str, SongInfo)>,
+        new: Vec<(&'static str, SongInfo)>,
+        renames: &'static [(&'static str, &'static str)],
+        unmatched: &'static [&'static str],
+    }
+
+    #[test]
+    fn matching_rules() {
+        let tags = Some(("Artist", "Title"));
+        let cases = vec![
+            Case {
+                name: "same MusicBrainz ID after moving",
+                old: vec![("mbid:1", song("a/song.flac", tags, Some(200)))],
+                new: vec![("mbid:1", song("b/song.flac", tags, Some(200)))],
+                renames: &[],
+                unmatched: &[],
+            },
+            Case {
+                name: "duration within tolerance",
+                old: vec![("old", song("a/song.flac", tags, Some(200)))],
+                new: vec![("new", song("b/other.flac", Some(("artist ", "TITLE")), Some(201)))],
+                renames: &[("old", "new")],
+                unmatched: &[],
+            },
+            Case {
+                name: "duration beyond tolerance",
+                old: vec![("old", song("a/song.flac", tags, Some(200)))],
+                new: vec![("new", song("b/song.flac", tags, Some(202)))],
+                renames: &[],
+                unmatched: &["old"],
+            },
+            Case {
+                name: "only one duration known",
+                old: vec![("old", song("a/song.flac", tags, None))],
+                new: vec![("new", song("b/song.flac", tags, Some(200)))],
+                renames: &[],
+                unmatched: &["old"],
+            },
+            Case {
+                name: "conflicting tags",
+                old: vec![("old", song("a/song.flac", tags, Some(200)))],
+                new: vec![("new", song("b/song.flac", Some(("Artist", "Other")), Some(200)))],
+                renames: &[],
+                unmatched: &["old"],
+            },
+            Case {
+                name: "no tags, same file name",
+                old: vec![("old", song("a/song.flac", None, Some(200)))],
+                new: vec![("new", song("b/song.flac", None, Some(200)))],
+                renames: &[("old", "new")],
+                unmatched: &[],
+            },
+            Case {
+                name: "no tags, different file name",
+                old: vec![("old", song("a/song.flac", None, Some(200)))],
+                new: vec![("new", song("b/other.flac", None, Some(200)))],
+                renames: &[],
+                unmatched: &["old"],
+            },
+            Case {
+                name: "ambiguous match",
+                old: vec![("old", song("a/song.flac", tags, Some(200)))],
+                new: vec![
+                    ("new1", song("b/song.flac", tags, Some(200))),
+                    ("new2", song("c/song.flac", tags, Some(200))),
+                ],
+                renames: &[],
+                unmatched: &["old"],
+            },
+            Case {
+                name: "match already claimed",
+                old: vec![
+                    ("old1", song("a/song.flac", tags, Some(200))),
+                    ("old2", song("b/song.flac", tags, Some(200))),
+                ],
+                new: vec![("new", song("c/song.flac", tags, Some(200)))],
+                renames: &[("old1", "new")],
+                unmatched: &["old2"],
+            },
+            Case {
+                name: "nothing learned",
+                old: vec![("unlearned", song("a/song.flac", tags, Some(200)))],
+                new: vec![("new", song("b/song.flac", tags, Some(200)))],
+                renames: &[],
+                unmatched: &[],
+            },
+        ];
+
+        for case in cases {
+            let old = index(&case.old);
+            let mut new = index(&case.new);
+            let result = reconcile(&old, &mut new, |id| id != "unlearned");
+
+            let renames = case.renames
+                .iter()
+                .map(|&(from, to)| (from.to_owned(), to.to_owned()))
+                .collect::<HashMap<_, _>>();
+
+            assert_eq!(result.renames, renames, "{}", case.name);
+            assert_eq!(result.unmatched, case.unmatched, "{}", case.name);
+
+            for &id in case.unmatched {
+                let info = new.get(id).expect("Unmatched song not kept");
+                assert!(info.missing, "{}", case.name);
+            }
+
+            assert_eq!(new.len(), case.new.len() + case.unmatched.len(), "{}", case.name);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+/*
+ * settings.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use config::{Config, StrategyConfig};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Daemon settings which can be changed while it is running,
+/// shared between the main loop and the control socket.
+#[derive(Debug)]
+pub struct Settings {
+    recommend: AtomicBool,
+    strategy: Mutex<StrategyConfig>,
+}
+
+impl Settings {
+    pub fn new(config: &Config) -> Self {
+        Settings {
+            recommend: AtomicBool::new(config.queue.recommend),
+            strategy: Mutex::new(config.selection),
+        }
+    }
+
+    /// Whether the queue is managed at all.
+    /// When off, the daemon only listens and learns.
+    pub fn recommend(&self) -> bool {
+        self.recommend.load(Ordering::SeqCst)
+    }
+
+    pub fn set_recommend(&self, value: bool) {
+        self.recommend.store(value, Ordering::SeqCst);
+    }
+
+    pub fn strategy(&self) -> StrategyConfig {
+        *self.strategy.lock().expect("Settings lock poisoned")
+    }
+
+    pub fn set_strategy(&self, strategy: StrategyConfig) {
+        *self.strategy.lock().expect("Settings lock poisoned") = strategy;
+    }
+}
@@ -0,0 +1,499 @@
+/*
+ * socket/mod.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod reply;
+mod request;
+
+#[doc(hidden)]
+pub use self::request::split_args;
+
+use {Error, Result, StdResult};
+use config::{Config, MpdConfig, StrategyConfig};
+use events::{Event, EventBus};
+use identity::identify;
+use model::Model;
+use player::{ListenTracker, Player};
+use self::reply::{Failure, Reply, Stats, Successor, Transition};
+use self::request::Request;
+use settings::Settings;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+type CommandResult = StdResult<Reply, Failure>;
+
+/// How many transitions `TOP` lists by default.
+const DEFAULT_TOP: usize = 10;
+
+/// How much evidence `LIKE` and `DISLIKE` add by default.
+const DEFAULT_WEIGHT: f32 = 1.0;
+
+/// How often a subscriber without events is checked for having closed
+/// its connection, so its thread doesn't outlive it for long.
+const SUBSCRIBER_CHECK: Duration = Duration::from_secs(10);
+
+/// The protocol spoken on a connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Protocol {
+    Text,
+    Json,
+}
+
+/// Listens for connections on the control socket.
+///
+/// Each connection sends commands one per line, and each command is
+/// answered like in mpd's own protocol: zero or more lines of data,
+/// then either `OK`, or `ERR` followed by the kind of failure (`NOCMD`,
+/// `INVARG` or `FAILED`) and a message.
+///
+/// After `PROTO json`, the connection instead sends a JSON object per
+/// line, and each is answered with a JSON object on a single line.
+///
+/// After `SUBSCRIBE` is answered, the connection only receives events,
+/// each framed like a reply, until it is closed.
+#[derive(Debug)]
+pub struct SocketServer {
+    listener: UnixListener,
+    handler: Arc<Handler>,
+}
+
+impl SocketServer {
+    pub fn bind(
+        config: &Config,
+        settings: Arc<Settings>,
+        model: Arc<Mutex<Model>>,
+        tracker: Arc<Mutex<ListenTracker>>,
+        events: Arc<EventBus>,
+    ) -> Result<Self> {
+        let path = &config.daemon.socket;
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        let handler = Handler {
+            mpd: config.mpd.clone(),
+            settings,
+            model,
+            tracker,
+            events,
+        };
+
+        Ok(SocketServer {
+            listener,
+            handler: Arc::new(handler),
+        })
+    }
+
+    /// Accepts the next connection, serving it on its own thread.
+    pub fn wait(&self) -> Result<()> {
+        let (stream, _) = self.listener.accept()?;
+        let handler = Arc::clone(&self.handler);
+
+        thread::spawn(move || {
+            if let Err(e) = handler.serve(stream) {
+                debug!("Control connection closed: {}", e);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Handler {
+    mpd: MpdConfig,
+    settings: Arc<Settings>,
+    model: Arc<Mutex<Model>>,
+    tracker: Arc<Mutex<ListenTracker>>,
+    events: Arc<EventBus>,
+}
+
+impl Handler {
+    fn serve(&self, stream: UnixStream) -> Result<()> {
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let mut protocol = Protocol::Text;
+
+        for line in reader.lines() {
+            let mut line = line?;
+            if line.ends_with('\r') {
+                line.pop();
+            }
+
+            if protocol == Protocol::Text && line.to_ascii_uppercase().starts_with("PROTO ") {
+                match line[6..].to_ascii_lowercase().as_str() {
+                    "json" => protocol = Protocol::Json,
+                    "text" => (),
+                    _ => {
+                        writeln!(writer, "ERR {}", Failure::invalid("Expected json or text"))?;
+                        writer.flush()?;
+                        continue;
+                    },
+                }
+
+                writeln!(writer, "OK")?;
+                writer.flush()?;
+                continue;
+            }
+
+            let request = match protocol {
+                Protocol::Text => Request::parse(&mut line),
+                Protocol::Json => Request::from_json(&line),
+            };
+
+            let quit = matches!(request, Ok(Request::Quit));
+
+            // Subscribe before replying, so no event is missed in between
+            let events = match request {
+                Ok(Request::Subscribe) => Some(self.events.subscribe()),
+                _ => None,
+            };
+
+            let result = request.and_then(|request| self.execute(&request));
+
+            if let Err(ref e) = result {
+                match *e {
+                    Failure::Error(ref e) => {
+                        warn!("Error handling socket command {:?}: {}", line, e);
+                        self.events.publish(Event::Error {
+                            message: e.to_string(),
+                        });
+                    },
+                    _ => debug!("Invalid socket command {:?}: {}", line, e),
+                }
+            }
+
+            match protocol {
+                Protocol::Text => match result {
+                    Ok(ref reply) => {
+                        for line in reply.lines() {
+                            writeln!(writer, "{}", line)?;
+                        }
+
+                        writeln!(writer, "OK")?;
+                    },
+                    Err(ref e) => writeln!(writer, "ERR {}", e)?,
+                },
+                Protocol::Json => writeln!(writer, "{}", reply::to_json(&result))?,
+            }
+
+            writer.flush()?;
+
+            if quit {
+                info!("Exiting by request");
+                exit(0);
+            }
+
+            if let Some(events) = events {
+                return self.stream_events(events, &mut writer, protocol);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends events to a subscriber until its connection is closed.
+    ///
+    /// Only this thread waits on a slow subscriber, since events are
+    /// dropped once its buffer is full. Anything else the subscriber
+    /// sends is ignored, but reading it is how a closed connection
+    /// is noticed while there are no events to send.
+    fn stream_events(
+        &self,
+        events: Receiver<Event>,
+        writer: &mut BufWriter<UnixStream>,
+        protocol: Protocol,
+    ) -> Result<()> {
+        debug!("Sending events to subscriber");
+
+        let mut stream = writer.get_ref().try_clone()?;
+        stream.set_read_timeout(Some(Duration::from_millis(1)))?;
+
+        loop {
+            let event = match events.recv_timeout(SUBSCRIBER_CHECK) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => match closed(&mut stream)? {
+                    true => return Ok(()),
+                    false => continue,
+                },
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+
+            match protocol {
+                Protocol::Text => {
+                    for line in reply::event_lines(&event) {
+                        writeln!(writer, "{}", line)?;
+                    }
+
+                    writeln!(writer, "OK")?;
+                },
+                Protocol::Json => writeln!(writer, "{}", reply::event_json(&event))?,
+            }
+
+            writer.flush()?;
+        }
+    }
+
+    fn execute(&self, request: &Request) -> CommandResult {
+        match *request {
+            Request::Ping | Request::Subscribe | Request::Quit => Ok(Reply::Done),
+            Request::Recommend { enabled } => self.recommend(enabled),
+            Request::Strategy(strategy) => self.set_strategy(strategy),
+            Request::Reconcile => self.reconcile(),
+            Request::Gc { dry_run } => self.collect_garbage(dry_run),
+            Request::Next { ref song } => self.next(song),
+            Request::Top { count } => self.top(count.unwrap_or(DEFAULT_TOP)),
+            Request::Predict => self.predict(),
+            Request::Stats => self.stats(),
+            Request::Like { ref from, ref to, weight } => self.train(from, to, weight, 1.0),
+            Request::Dislike { ref from, ref to, weight } => self.train(from, to, weight, -1.0),
+            Request::Forget { ref song } => self.forget(song),
+            Request::LikeCurrent { weight } => self.train_current(weight, 1.0),
+            Request::DislikeCurrent { weight } => self.train_current(weight, -1.0),
+        }
+    }
+
+    /// Turns queue management on or off, keeping the setting across
+    /// restarts. Without a setting, replies with the current one.
+    fn recommend(&self, enabled: Option<bool>) -> CommandResult {
+        let value = match enabled {
+            Some(value) => value,
+            None => {
+                return Ok(Reply::Recommend {
+                    recommend: self.settings.recommend(),
+                })
+            },
+        };
+
+        info!("Setting recommendation to {}", value);
+        self.settings.set_recommend(value);
+        self.model
+            .lock()
+            .expect("Model lock poisoned")
+            .save_recommend(value)?;
+        self.events.publish(Event::Recommend { enabled: value });
+
+        // The main loop is waiting on mpd, so wake it to update the queue now
+        if let Err(e) = Player::new(&self.mpd).and_then(|mut player| player.notify()) {
+            warn!("Unable to notify main loop of new setting: {}", e);
+        }
+
+        Ok(Reply::Done)
+    }
+
+    fn set_strategy(&self, strategy: StrategyConfig) -> CommandResult {
+        if !strategy.is_valid() {
+            return Err(Failure::invalid("Invalid strategy parameter"));
+        }
+
+        info!("Setting selection strategy to {:?}", strategy);
+        self.settings.set_strategy(strategy);
+        Ok(Reply::Done)
+    }
+
+    /// Matches songs which have moved in the library, replying with
+    /// the number matched and each song which couldn't be.
+    fn reconcile(&self) -> CommandResult {
+        // Listing the library can be slow, so do it before locking the model
+        let songs = Player::new(&self.mpd)?.songs()?;
+        let result = self.model
+            .lock()
+            .expect("Model lock poisoned")
+            .refresh_library(&songs)?;
+
+        Ok(Reply::Reconcile {
+            renamed: result.renames.len(),
+            unmatched: result.unmatched,
+        })
+    }
+
+    /// Removes songs deleted from the library, or on a dry
+    /// run, only lists them. Replies with each of the songs.
+    fn collect_garbage(&self, dry_run: bool) -> CommandResult {
+        let library = Player::new(&self.mpd)?.songs()?;
+        let songs = self.model
+            .lock()
+            .expect("Model lock poisoned")
+            .collect_garbage(&library, dry_run)?;
+
+        Ok(Reply::Songs { songs })
+    }
+
+    /// Lists the learned successors of a song, given by its URI or
+    /// identity, with the probability of choosing each proportionally.
+    fn next(&self, song: &str) -> CommandResult {
+        let model = self.model.lock().expect("Model lock poisoned");
+        let id = find_song(&model, song)?;
+        Ok(successors(&model, &[id]))
+    }
+
+    /// Lists the transitions between single songs
+    /// most likely to be enjoyed.
+    fn top(&self, count: usize) -> CommandResult {
+        let mut model = self.model.lock().expect("Model lock poisoned");
+        let transitions = model
+            .top_transitions(count)?
+            .into_iter()
+            .map(|(from, to, edge)| Transition {
+                from: model.display(&from).to_owned(),
+                to: model.display(&to).to_owned(),
+                successes: edge.successes,
+                failures: edge.failures,
+            })
+            .collect();
+
+        Ok(Reply::Transitions { transitions })
+    }
+
+    /// Lists what could be chosen to follow the current song,
+    /// given the songs the daemon heard before it.
+    fn predict(&self) -> CommandResult {
+        let history = {
+            let tracker = self.tracker.lock().expect("Tracker lock poisoned");
+            let current = match tracker.current() {
+                Some(song) => song,
+                None => return Err(Error::StaticMsg("Nothing is playing").into()),
+            };
+
+            tracker
+                .history()
+                .chain(Some(current))
+                .map(identify)
+                .collect::<Vec<_>>()
+        };
+
+        let model = self.model.lock().expect("Model lock poisoned");
+        Ok(successors(&model, &history))
+    }
+
+    /// Replies with the size of the chain and of its storage.
+    fn stats(&self) -> CommandResult {
+        let mut model = self.model.lock().expect("Model lock poisoned");
+        let storage = model.storage_stats()?;
+
+        Ok(Reply::Stats(Stats {
+            songs: model.chain().items().len(),
+            contexts: model.chain().context_count(),
+            transitions: model.chain().transition_count(),
+            library: model.songs().len(),
+            stored_transitions: storage.associations,
+            stored_tag_transitions: storage.tag_associations,
+            storage_bytes: storage.bytes,
+        }))
+    }
+
+    /// Adds evidence that one song should, or with a negative `sign`
+    /// should not, follow another. Takes both songs, by their URIs or
+    /// identities, and optionally how much evidence to add.
+    fn train(&self, from: &str, to: &str, weight: Option<f32>, sign: f32) -> CommandResult {
+        let weight = check_weight(weight)?;
+        let mut model = self.model.lock().expect("Model lock poisoned");
+        let from = find_song(&model, from)?;
+        let to = find_song(&model, to)?;
+
+        info!("Training {} -> {} by {}", from, to, sign * weight);
+        model.observe(&[from], &to, sign * weight)?;
+        Ok(Reply::Done)
+    }
+
+    /// Like `train`, but for the transition into the current song,
+    /// following the songs the daemon heard before it.
+    fn train_current(&self, weight: Option<f32>, sign: f32) -> CommandResult {
+        let weight = check_weight(weight)?;
+        let (history, next) = {
+            let tracker = self.tracker.lock().expect("Tracker lock poisoned");
+            let next = match tracker.current() {
+                Some(song) => identify(song),
+                None => return Err(Error::StaticMsg("Nothing is playing").into()),
+            };
+
+            (tracker.history().map(identify).collect::<Vec<_>>(), next)
+        };
+
+        if history.is_empty() {
+            return Err(Error::StaticMsg("No song before the current one").into());
+        }
+
+        let mut model = self.model.lock().expect("Model lock poisoned");
+
+        info!("Training {:?} -> {} by {}", history, next, sign * weight);
+        model.observe(&history, &next, sign * weight)?;
+        Ok(Reply::Done)
+    }
+
+    /// Forgets every transition out of or into a song,
+    /// replying with how many were removed.
+    fn forget(&self, song: &str) -> CommandResult {
+        let mut model = self.model.lock().expect("Model lock poisoned");
+        let id = find_song(&model, song)?;
+        let removed = model.forget(&id)?;
+        Ok(Reply::Removed { removed })
+    }
+}
+
+fn find_song(model: &Model, song: &str) -> StdResult<String, Failure> {
+    match model.find_song(song) {
+        Some(id) => Ok(id.to_owned()),
+        None => Err(Failure::invalid(format!("Unknown song '{}'", song))),
+    }
+}
+
+/// Whether the other end of a connection has closed it, discarding
+/// anything it sent. The stream must have a read timeout set.
+fn closed(stream: &mut UnixStream) -> io::Result<bool> {
+    let mut buffer = [0; 256];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => return Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => return Ok(true),
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                return Ok(false)
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn check_weight(weight: Option<f32>) -> StdResult<f32, Failure> {
+    match weight.unwrap_or(DEFAULT_WEIGHT) {
+        weight if weight.is_finite() && weight > 0.0 => Ok(weight),
+        _ => Err(Failure::invalid("Weight must be a positive number")),
+    }
+}
+
+fn successors(model: &Model, history: &[String]) -> Reply {
+    let successors = model
+        .successors(history)
+        .into_iter()
+        .map(|(id, probability)| Successor {
+            song: model.display(id).to_owned(),
+            probability,
+        })
+        .collect();
+
+    Reply::Successors { successors }
+}
@@ -0,0 +1,292 @@
+/*
+ * socket/reply.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use {Error, StdResult};
+use events::Event;
+use serde_json;
+use std::borrow::Cow;
+use std::fmt;
+
+/// The result of a command, sent as `key: value` lines in the
+/// text protocol, or as the `result` object in the JSON protocol.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Reply {
+    Done,
+    Recommend {
+        recommend: bool,
+    },
+    Reconcile {
+        renamed: usize,
+        unmatched: Vec<String>,
+    },
+    Songs {
+        songs: Vec<String>,
+    },
+    Successors {
+        successors: Vec<Successor>,
+    },
+    Transitions {
+        transitions: Vec<Transition>,
+    },
+    Stats(Stats),
+    Removed {
+        removed: usize,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Successor {
+    pub song: String,
+    pub probability: f32,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub from: String,
+    pub to: String,
+    pub successes: f32,
+    pub failures: f32,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub songs: usize,
+    pub contexts: usize,
+    pub transitions: usize,
+    pub library: usize,
+    pub stored_transitions: u64,
+    pub stored_tag_transitions: u64,
+    pub storage_bytes: u64,
+}
+
+impl Reply {
+    /// The lines sent in the text protocol, before `OK`.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        match *self {
+            Reply::Done => (),
+            Reply::Recommend { recommend } => {
+                let value = if recommend { "on" } else { "off" };
+                lines.push(format!("recommend: {}", value));
+            },
+            Reply::Reconcile { renamed, ref unmatched } => {
+                lines.push(format!("renamed: {}", renamed));
+                lines.push(format!("unmatched: {}", unmatched.len()));
+                lines.extend(unmatched.iter().map(|id| format!("song: {}", id)));
+            },
+            Reply::Songs { ref songs } => {
+                lines.push(format!("count: {}", songs.len()));
+                lines.extend(songs.iter().map(|id| format!("song: {}", id)));
+            },
+            Reply::Successors { ref successors } => {
+                for successor in successors {
+                    lines.push(format!("song: {}", successor.song));
+                    lines.push(format!("probability: {:.4}", successor.probability));
+                }
+            },
+            Reply::Transitions { ref transitions } => {
+                for transition in transitions {
+                    lines.push(format!("from: {}", transition.from));
+                    lines.push(format!("to: {}", transition.to));
+                    lines.push(format!("successes: {:.2}", transition.successes));
+                    lines.push(format!("failures: {:.2}", transition.failures));
+                }
+            },
+            Reply::Stats(ref stats) => {
+                lines.push(format!("songs: {}", stats.songs));
+                lines.push(format!("contexts: {}", stats.contexts));
+                lines.push(format!("transitions: {}", stats.transitions));
+                lines.push(format!("library: {}", stats.library));
+                lines.push(format!("stored_transitions: {}", stats.stored_transitions));
+                lines.push(format!("stored_tag_transitions: {}", stats.stored_tag_transitions));
+                lines.push(format!("storage_bytes: {}", stats.storage_bytes));
+            },
+            Reply::Removed { removed } => lines.push(format!("removed: {}", removed)),
+        }
+
+        lines
+    }
+}
+
+/// The lines sent in the text protocol for an event, before `OK`.
+pub fn event_lines(event: &Event) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    match *event {
+        Event::SongStarted { ref song } => {
+            lines.push("event: song-started".to_owned());
+            lines.push(format!("song: {}", song));
+        },
+        Event::Learned { ref from, ref to, listen, delta } => {
+            lines.push("event: learned".to_owned());
+            if let Some(ref from) = *from {
+                lines.push(format!("from: {}", from));
+            }
+            lines.push(format!("to: {}", to));
+            lines.push(format!("listen: {}", listen.as_str()));
+            lines.push(format!("delta: {:.2}", delta));
+        },
+        Event::Enqueued { ref songs } => {
+            lines.push("event: enqueued".to_owned());
+            lines.extend(songs.iter().map(|song| format!("song: {}", song)));
+        },
+        Event::Recommend { enabled } => {
+            let value = if enabled { "on" } else { "off" };
+            lines.push("event: recommend".to_owned());
+            lines.push(format!("recommend: {}", value));
+        },
+        Event::Error { ref message } => {
+            lines.push("event: error".to_owned());
+            lines.push(format!("message: {}", message));
+        },
+        Event::Dropped { count } => {
+            lines.push("event: dropped".to_owned());
+            lines.push(format!("count: {}", count));
+        },
+    }
+
+    lines
+}
+
+/// Why a command failed. The kind of failure is sent after `ERR`, so
+/// that clients can tell mistakes in commands from other problems.
+#[derive(Debug)]
+pub enum Failure {
+    /// The request couldn't be parsed at all.
+    InvalidRequest(String),
+
+    /// There is no such command.
+    NoCommand(String),
+
+    /// The arguments are malformed, or refer to something unknown.
+    InvalidArgument(Cow<'static, str>),
+
+    /// The command was understood, but could not be carried out.
+    Error(Error),
+}
+
+impl Failure {
+    pub fn invalid<S: Into<Cow<'static, str>>>(message: S) -> Self {
+        Failure::InvalidArgument(message.into())
+    }
+
+    /// The error code sent in the JSON protocol. Failures to carry
+    /// out a command are told apart by the kind of error behind them.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Failure::InvalidRequest(_) => "invalid-request",
+            Failure::NoCommand(_) => "unknown-command",
+            Failure::InvalidArgument(_) => "invalid-argument",
+            Failure::Error(ref e) => e.code(),
+        }
+    }
+
+    fn message(&self) -> Cow<'_, str> {
+        match *self {
+            Failure::InvalidRequest(ref message) => message.as_str().into(),
+            Failure::NoCommand(ref call) => format!("Unknown command '{}'", call).into(),
+            Failure::InvalidArgument(ref message) => message.as_ref().into(),
+            Failure::Error(ref e) => e.to_string().into(),
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(error: Error) -> Self {
+        Failure::Error(error)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match *self {
+            Failure::NoCommand(_) => "NOCMD",
+            Failure::InvalidRequest(_) | Failure::InvalidArgument(_) => "INVARG",
+            Failure::Error(_) => "FAILED",
+        };
+
+        write!(f, "{} {}", kind, self.message())
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct JsonError<'a> {
+    code: &'static str,
+    message: Cow<'a, str>,
+}
+
+/// A reply as sent in the JSON protocol, on a single line.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum JsonReply<'a> {
+    Ok { ok: bool, result: &'a Reply },
+    Err { ok: bool, error: JsonError<'a> },
+}
+
+pub fn to_json(result: &StdResult<Reply, Failure>) -> String {
+    let reply = match *result {
+        Ok(ref reply) => JsonReply::Ok { ok: true, result: reply },
+        Err(ref failure) => JsonReply::Err {
+            ok: false,
+            error: JsonError {
+                code: failure.code(),
+                message: failure.message(),
+            },
+        },
+    };
+
+    serde_json::to_string(&reply).expect("Serializing reply failed")
+}
+
+/// An event as sent in the JSON protocol, on a single line.
+pub fn event_json(event: &Event) -> String {
+    serde_json::to_string(event).expect("Serializing event failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_replies() {
+        assert_eq!(to_json(&Ok(Reply::Done)), r#"{"ok":true,"result":null}"#);
+        assert_eq!(
+            to_json(&Ok(Reply::Removed { removed: 3 })),
+            r#"{"ok":true,"result":{"removed":3}}"#,
+        );
+        assert_eq!(
+            to_json(&Err(Failure::NoCommand("frob".to_owned()))),
+            r#"{"ok":false,"error":{"code":"unknown-command","message":"Unknown command 'frob'"}}"#,
+        );
+        assert_eq!(
+            to_json(&Err(Failure::Error(Error::StaticMsg("Nothing is playing")))),
+            r#"{"ok":false,"error":{"code":"failed","message":"Nothing is playing"}}"#,
+        );
+    }
+
+    #[test]
+    fn text_replies() {
+        assert_eq!(Reply::Done.lines(), Vec::<String>::new());
+        assert_eq!(Reply::Recommend { recommend: false }.lines(), vec!["recommend: off"]);
+        assert_eq!(Failure::invalid("Expected ON or OFF").to_string(), "INVARG Expected ON or OFF");
+    }
+}
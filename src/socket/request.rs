@@ -0,0 +1,351 @@
+/*
+ * socket/request.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use StdResult;
+use config::StrategyConfig;
+use serde_json::{self, Value};
+use super::Failure;
+use utils::empty_mut_str;
+
+/// A command sent over the control socket, in either protocol.
+///
+/// In the JSON protocol, the command is named by its `command` field,
+/// and its arguments are the other fields, for instance:
+///
+/// ```json
+/// {"command": "like", "from": "a.flac", "to": "b.flac", "weight": 2}
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Ping,
+    Recommend {
+        #[serde(default)]
+        enabled: Option<bool>,
+    },
+    Strategy(StrategyConfig),
+    Reconcile,
+    Gc {
+        #[serde(default)]
+        dry_run: bool,
+    },
+    Next {
+        song: String,
+    },
+    Top {
+        #[serde(default)]
+        count: Option<usize>,
+    },
+    Predict,
+    Stats,
+    Like {
+        from: String,
+        to: String,
+        #[serde(default)]
+        weight: Option<f32>,
+    },
+    Dislike {
+        from: String,
+        to: String,
+        #[serde(default)]
+        weight: Option<f32>,
+    },
+    Forget {
+        song: String,
+    },
+    LikeCurrent {
+        #[serde(default)]
+        weight: Option<f32>,
+    },
+    DislikeCurrent {
+        #[serde(default)]
+        weight: Option<f32>,
+    },
+    Subscribe,
+    Quit,
+}
+
+impl Request {
+    /// Parses a line of the text protocol.
+    pub fn parse(line: &mut str) -> StdResult<Self, Failure> {
+        let (call, arg) = split_cmd(line);
+        call.make_ascii_uppercase();
+
+        let request = match call as &str {
+            "PING" => Request::Ping,
+            "RECOMMEND" => {
+                arg.make_ascii_uppercase();
+
+                let enabled = match arg as &str {
+                    "" => None,
+                    "ON" => Some(true),
+                    "OFF" => Some(false),
+                    _ => return Err(Failure::invalid("Expected ON or OFF")),
+                };
+
+                Request::Recommend { enabled }
+            },
+            "STRATEGY" => {
+                arg.make_ascii_lowercase();
+
+                let (name, param) = split_cmd(arg);
+                let param = if param.is_empty() { None } else { Some(&*param) };
+
+                match StrategyConfig::parse(name, param) {
+                    Some(strategy) => Request::Strategy(strategy),
+                    None => return Err(Failure::invalid("Unknown strategy or invalid parameter")),
+                }
+            },
+            "RECONCILE" => Request::Reconcile,
+            "GC" => {
+                arg.make_ascii_uppercase();
+
+                let dry_run = match arg as &str {
+                    "" => false,
+                    "DRY" => true,
+                    _ => return Err(Failure::invalid("Expected DRY or nothing")),
+                };
+
+                Request::Gc { dry_run }
+            },
+            "NEXT" => Request::Next { song: one_song(arg)? },
+            "TOP" => {
+                let count = match arg as &str {
+                    "" => None,
+                    _ => Some(arg.parse().map_err(|_| Failure::invalid("Expected a number"))?),
+                };
+
+                Request::Top { count }
+            },
+            "PREDICT" => Request::Predict,
+            "STATS" => Request::Stats,
+            "LIKE" => {
+                let (from, to, weight) = two_songs(arg)?;
+                Request::Like { from, to, weight }
+            },
+            "DISLIKE" => {
+                let (from, to, weight) = two_songs(arg)?;
+                Request::Dislike { from, to, weight }
+            },
+            "FORGET" => Request::Forget { song: one_song(arg)? },
+            "LIKE-CURRENT" => Request::LikeCurrent { weight: weight(arg)? },
+            "DISLIKE-CURRENT" => Request::DislikeCurrent { weight: weight(arg)? },
+            "SUBSCRIBE" => Request::Subscribe,
+            "QUIT" => Request::Quit,
+            _ => return Err(Failure::NoCommand(call.to_owned())),
+        };
+
+        Ok(request)
+    }
+
+    /// Parses a line of the JSON protocol.
+    pub fn from_json(line: &str) -> StdResult<Self, Failure> {
+        let value = serde_json::from_str::<Value>(line)
+            .map_err(|e| Failure::InvalidRequest(e.to_string()))?;
+
+        let name = match value.get("command").and_then(Value::as_str) {
+            Some(name) => name.to_owned(),
+            None => return Err(Failure::InvalidRequest("Missing command".into())),
+        };
+
+        if serde_json::from_value::<CommandName>(Value::from(name.as_str())).is_err() {
+            return Err(Failure::NoCommand(name));
+        }
+
+        serde_json::from_value(value).map_err(|e| Failure::invalid(e.to_string()))
+    }
+}
+
+/// The names of the commands in the JSON protocol, as in `Request`.
+/// Telling these apart first means that unknown values among the
+/// arguments, such as strategy names, aren't mistaken for an unknown command.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum CommandName {
+    Ping,
+    Recommend,
+    Strategy,
+    Reconcile,
+    Gc,
+    Next,
+    Top,
+    Predict,
+    Stats,
+    Like,
+    Dislike,
+    Forget,
+    LikeCurrent,
+    DislikeCurrent,
+    Subscribe,
+    Quit,
+}
+
+fn split_cmd(command: &mut str) -> (&mut str, &mut str) {
+    match command.find(' ') {
+        Some(idx) => {
+            let (call, rest) = command.split_at_mut(idx);
+            (call, &mut rest[1..])
+        },
+        None => (command, empty_mut_str()),
+    }
+}
+
+/// Splits arguments on spaces, except within double quotes, where
+/// a backslash escapes the next character, like in mpd's protocol.
+pub fn split_args(arg: &str) -> StdResult<Vec<String>, Failure> {
+    let mut args = Vec::new();
+    let mut chars = arg.chars().peekable();
+
+    loop {
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+
+        let mut current = String::new();
+        match chars.peek() {
+            None => return Ok(args),
+            Some(&'"') => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => current.push(c),
+                            None => return Err(Failure::invalid("Unterminated quote")),
+                        },
+                        Some(c) => current.push(c),
+                        None => return Err(Failure::invalid("Unterminated quote")),
+                    }
+                }
+            },
+            Some(_) => {
+                while let Some(&c) = chars.peek() {
+                    if c == ' ' {
+                        break;
+                    }
+
+                    current.push(c);
+                    chars.next();
+                }
+            },
+        }
+
+        args.push(current);
+    }
+}
+
+fn one_song(arg: &str) -> StdResult<String, Failure> {
+    match split_args(arg)?.as_slice() {
+        [song] => Ok(song.clone()),
+        _ => Err(Failure::invalid("Expected one song")),
+    }
+}
+
+fn two_songs(arg: &str) -> StdResult<(String, String, Option<f32>), Failure> {
+    match split_args(arg)?.as_slice() {
+        [from, to] => Ok((from.clone(), to.clone(), None)),
+        [from, to, weight] => Ok((from.clone(), to.clone(), Some(parse_weight(weight)?))),
+        _ => Err(Failure::invalid("Expected two songs and an optional weight")),
+    }
+}
+
+fn weight(arg: &str) -> StdResult<Option<f32>, Failure> {
+    match arg {
+        "" => Ok(None),
+        _ => parse_weight(arg).map(Some),
+    }
+}
+
+fn parse_weight(weight: &str) -> StdResult<f32, Failure> {
+    weight
+        .parse()
+        .map_err(|_| Failure::invalid("Weight must be a positive number"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> StdResult<Request, Failure> {
+        Request::parse(&mut line.to_owned())
+    }
+
+    #[test]
+    fn split_quoted() {
+        let args = split_args(r#"a  "b c" "d \"e\" \\f" "" g"#).expect("Arguments not split");
+        assert_eq!(args, vec!["a", "b c", r#"d "e" \f"#, "", "g"]);
+
+        assert_eq!(split_args("").expect("Arguments not split"), Vec::<String>::new());
+        assert!(split_args(r#""a"#).is_err());
+        assert!(split_args(r#""a\"#).is_err());
+    }
+
+    #[test]
+    fn parse_text() {
+        assert_eq!(
+            parse(r#"like "Artist/01 A Song.flac" b.flac 2.5"#).expect("Request not parsed"),
+            Request::Like {
+                from: "Artist/01 A Song.flac".to_owned(),
+                to: "b.flac".to_owned(),
+                weight: Some(2.5),
+            },
+        );
+
+        assert_eq!(
+            parse("STRATEGY Top-K 3").expect("Request not parsed"),
+            Request::Strategy(StrategyConfig::TopK { k: 3 }),
+        );
+
+        assert_eq!(parse("recommend").expect("Request not parsed"), Request::Recommend { enabled: None });
+        assert!(matches!(parse("STRATEGY softmax"), Err(Failure::InvalidArgument(_))));
+        assert!(matches!(parse("NEXT a b"), Err(Failure::InvalidArgument(_))));
+        assert!(matches!(parse("FROB a"), Err(Failure::NoCommand(ref call)) if call == "FROB"));
+    }
+
+    #[test]
+    fn parse_json() {
+        assert_eq!(
+            Request::from_json(r#"{"command": "like", "from": "a b.flac", "to": "c.flac"}"#)
+                .expect("Request not parsed"),
+            Request::Like {
+                from: "a b.flac".to_owned(),
+                to: "c.flac".to_owned(),
+                weight: None,
+            },
+        );
+
+        assert_eq!(
+            Request::from_json(r#"{"command": "like-current"}"#).expect("Request not parsed"),
+            Request::LikeCurrent { weight: None },
+        );
+
+        let invalid = |line| matches!(Request::from_json(line), Err(Failure::InvalidArgument(_)));
+        assert!(invalid(r#"{"command": "strategy", "strategy": "softmax"}"#));
+        assert!(invalid(r#"{"command": "strategy", "strategy": "strategy"}"#));
+        assert!(invalid(r#"{"command": "next"}"#));
+
+        let unparsed = |line| matches!(Request::from_json(line), Err(Failure::InvalidRequest(_)));
+        assert!(unparsed(r#"{"song": "a.flac"}"#));
+        assert!(unparsed("PING"));
+
+        let unknown = Request::from_json(r#"{"command": "frob"}"#);
+        assert!(matches!(unknown, Err(Failure::NoCommand(ref name)) if name == "frob"));
+    }
+}
@@ -0,0 +1,103 @@
+/*
+ * tags.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use markov;
+use mpd::Song;
+
+/// Song metadata which transitions can also be learned over.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Tag {
+    Artist,
+    Album,
+    Genre,
+}
+
+impl Tag {
+    /// The name this tag is stored under.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Tag::Artist => "artist",
+            Tag::Album => "album",
+            Tag::Genre => "genre",
+        }
+    }
+
+    pub fn from_str(name: &str) -> Option<Self> {
+        match name {
+            "artist" => Some(Tag::Artist),
+            "album" => Some(Tag::Album),
+            "genre" => Some(Tag::Genre),
+            _ => None,
+        }
+    }
+
+    /// The name of this tag in the mpd protocol.
+    pub fn mpd_name(self) -> &'static str {
+        match self {
+            Tag::Artist => "Artist",
+            Tag::Album => "Album",
+            Tag::Genre => "Genre",
+        }
+    }
+
+    pub fn value(self, song: &Song) -> Option<&str> {
+        if let (Tag::Artist, Some(ref artist)) = (self, &song.artist) {
+            return Some(artist);
+        }
+
+        song.tags
+            .iter()
+            .find(|(name, _)| name == self.mpd_name())
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// First-order chains over song metadata rather than individual songs.
+/// These fill in for songs which have little data of their own, since
+/// even a new song usually shares an artist or album with known ones.
+///
+/// Chains are kept in the order they should be consulted in.
+#[derive(Debug, Clone)]
+pub struct TagChains {
+    chains: Vec<(Tag, markov::Chain<String>)>,
+}
+
+impl TagChains {
+    pub fn new(tags: &[Tag], half_life: Option<f64>) -> Self {
+        let chains = tags
+            .iter()
+            .map(|&tag| (tag, markov::Chain::new(1).with_half_life(half_life)))
+            .collect();
+
+        TagChains { chains }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Tag, markov::Chain<String>)> {
+        self.chains.iter()
+    }
+
+    pub fn get_mut(&mut self, tag: Tag) -> Option<&mut markov::Chain<String>> {
+        self.chains
+            .iter_mut()
+            .find(|&&mut (t, _)| t == tag)
+            .map(|&mut (_, ref mut chain)| chain)
+    }
+}
@@ -19,19 +19,29 @@
  */
 
 use std::{env, str};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 lazy_static! {
     pub static ref HOME_DIR: PathBuf = env::home_dir().expect("Unable to get home directory");
 }
 
+pub fn expand_home(path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => HOME_DIR.join(rest),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
 #[inline]
 pub fn empty_mut_str() -> &'static mut str {
     unsafe { str::from_utf8_unchecked_mut(&mut []) }
 }
 
-#[inline]
-pub fn sigmoid(n: f32) -> f32 {
-    let en = n.exp();
-    en / (en + 1.0)
+/// The current time, in seconds since the Unix epoch.
+pub fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(_) => 0,
+    }
 }
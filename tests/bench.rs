@@ -0,0 +1,244 @@
+/*
+ * tests/bench.rs
+ *
+ * markov-music - A music player that uses Markov chains to choose songs
+ * Copyright (c) 2017-2018 Ammon Smith
+ *
+ * markov-music is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * markov-music is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with markov-music.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Benchmarks of the chain's memory use and sampling speed, including
+//! a comparison against the previous representation, which keyed
+//! nested hash maps by song path.
+//!
+//! These are kept in their own test binary, so that the allocator
+//! counting memory use doesn't apply to any other tests. They are
+//! ignored by default, as they are slow and only meaningful in
+//! release mode. Run them with:
+//!
+//! ```text
+//! cargo test --release --test bench -- --ignored --nocapture
+//! ```
+
+extern crate markov_music;
+extern crate rand;
+
+use markov_music::internals::{weighted_index, Chain, Choice, Edge, Interner, Proportional, SelectionStrategy};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+const SONGS: usize = 50_000;
+const SUCCESSORS: usize = 20;
+const LOOKUPS: usize = 100_000;
+const HUB_SUCCESSORS: usize = 10_000;
+
+/// Tracks how many bytes are currently allocated, to measure
+/// how much memory each representation retains.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Held by each benchmark, so that neither measures the other's
+/// allocations or competes with it for time.
+static RUNNING: Mutex<()> = Mutex::new(());
+
+fn rng() -> XorShiftRng {
+    XorShiftRng::from_seed([0x0c2e_8a1d, 0x5f3b_94e7, 0x7d10_c6a2, 0x2b9f_e453])
+}
+
+/// Song identities shaped like the ones the daemon actually stores.
+fn library() -> Vec<String> {
+    (0..SONGS)
+        .map(|n| format!("tags:artist {}\tsome song title number {}\t{}", n / 100, n, 180 + n % 240))
+        .collect()
+}
+
+/// Random first-order transitions over the library.
+fn transitions(library: &[String]) -> Vec<(usize, usize, Edge)> {
+    let mut rng = rng();
+    let mut transitions = Vec::with_capacity(SONGS * SUCCESSORS);
+
+    for from in 0..library.len() {
+        for _ in 0..SUCCESSORS {
+            let to = rng.gen_range(0, library.len());
+            let edge = Edge {
+                successes: rng.gen_range(0.0, 10.0),
+                failures: rng.gen_range(0.0, 10.0),
+                updated: 0,
+            };
+
+            transitions.push((from, to, edge));
+        }
+    }
+
+    transitions
+}
+
+/// Proportional choice without alias tables, walking every edge.
+#[derive(Debug)]
+struct Linear;
+
+impl SelectionStrategy for Linear {
+    fn choose(&self, weights: &[f32], rng: &mut dyn Rng) -> Option<Choice<usize>> {
+        weighted_index(weights, rng).map(Choice::Pick)
+    }
+}
+
+/// The previous layout of `Chain<String>`.
+type NestedChain = HashMap<Vec<String>, HashMap<String, Edge>>;
+
+/// Chooses the way the previous chain did, sorting
+/// successors by key before every choice.
+fn nested_next<'a>(chain: &'a NestedChain, context: &[String], rng: &mut dyn Rng) -> Option<&'a String> {
+    let probs = chain.get(context)?;
+    let mut entries = probs.iter().collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    let posteriors = entries
+        .iter()
+        .map(|&(_, edge)| edge.alpha_beta())
+        .collect::<Vec<_>>();
+
+    match Proportional.choose_posterior(&posteriors, rng)? {
+        Choice::Pick(idx) => Some(entries[idx].0),
+        Choice::Explore => None,
+    }
+}
+
+fn measure<T, F: FnOnce() -> T>(f: F) -> (T, usize) {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let value = f();
+    let after = ALLOCATED.load(Ordering::SeqCst);
+
+    (value, after.saturating_sub(before))
+}
+
+fn report(name: &str, bytes: usize, nanos: f64) {
+    println!(
+        "{:>8}: {:>8.1} MiB retained, {:>8.0} ns per next()",
+        name,
+        bytes as f64 / (1024.0 * 1024.0),
+        nanos,
+    );
+}
+
+#[test]
+#[ignore]
+fn chain_memory_and_latency() {
+    let _running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+    let library = library();
+    let transitions = transitions(&library);
+
+    let (nested, nested_bytes) = measure(|| {
+        let mut chain = NestedChain::new();
+        for &(from, to, edge) in &transitions {
+            chain
+                .entry(vec![library[from].clone()])
+                .or_default()
+                .insert(library[to].clone(), edge);
+        }
+
+        chain
+    });
+
+    let ((interner, interned), interned_bytes) = measure(|| {
+        let mut interner = Interner::new();
+        let mut chain = Chain::new(1);
+        for &(from, to, edge) in &transitions {
+            let from = interner.intern(&library[from]);
+            let to = interner.intern(&library[to]);
+            chain.set_weight(vec![from], to, edge);
+        }
+
+        (interner, chain)
+    });
+
+    let mut rng = rng();
+    let states = (0..LOOKUPS)
+        .map(|_| rng.gen_range(0, library.len()))
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    for &state in &states {
+        let context = [library[state].clone()];
+        assert!(nested_next(&nested, &context, &mut rng).is_some());
+    }
+    let nested_nanos = start.elapsed().as_nanos() as f64 / LOOKUPS as f64;
+
+    let start = Instant::now();
+    for &state in &states {
+        let context = [interner.get(&library[state]).expect("Song not interned")];
+        let next = interned.next(&context, &Proportional, &mut rng);
+        assert!(next.is_some());
+    }
+    let interned_nanos = start.elapsed().as_nanos() as f64 / LOOKUPS as f64;
+
+    println!("{} songs, {} transitions each", SONGS, SUCCESSORS);
+    report("strings", nested_bytes, nested_nanos);
+    report("interned", interned_bytes, interned_nanos);
+
+    assert!(interned_bytes < nested_bytes, "Interned chain uses more memory");
+}
+
+#[test]
+#[ignore]
+fn hub_sampling() {
+    let _running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+    let mut rng = rng();
+    let mut chain = Chain::new(1);
+    for next in 1..=HUB_SUCCESSORS as u32 {
+        let edge = Edge {
+            successes: rng.gen_range(0.0, 10.0),
+            failures: rng.gen_range(0.0, 10.0),
+            updated: 0,
+        };
+
+        chain.set_weight(vec![0], next, edge);
+    }
+
+    let time = |strategy: &dyn SelectionStrategy, rng: &mut XorShiftRng| {
+        let start = Instant::now();
+        for _ in 0..LOOKUPS {
+            assert!(chain.next(&[0], strategy, rng).is_some());
+        }
+
+        start.elapsed().as_nanos() as f64 / LOOKUPS as f64
+    };
+
+    let linear = time(&Linear, &mut rng);
+    let alias = time(&Proportional, &mut rng);
+
+    println!("One song with {} successors", HUB_SUCCESSORS);
+    println!("{:>8}: {:>8.0} ns per next()", "linear", linear);
+    println!("{:>8}: {:>8.0} ns per next()", "alias", alias);
+}